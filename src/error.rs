@@ -0,0 +1,115 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Everything which can go wrong inside the library.
+#[derive(Debug)]
+pub enum Error {
+    /// reading or writing a file failed
+    Io { context: String, source: io::Error },
+    /// the data file couldn't be locked
+    Lock { path: PathBuf, source: io::Error },
+    /// someone else held the lock on the data file for too long
+    Locked { path: PathBuf },
+    /// a line wasn't a valid row
+    BadRow(String),
+    /// an expression couldn't be compiled into a regex
+    Regex { expr: String, source: regex::Error },
+    /// a setting or value was out of range
+    Invalid(String),
+}
+
+impl Error {
+    pub(crate) fn io<S: Into<String>>(context: S, source: io::Error) -> Error {
+        Error::Io {
+            context: context.into(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { context, .. } => write!(f, "{}", context),
+            Error::Lock { path, .. } => write!(f, "locking {:?}", path),
+            Error::Locked { path } => {
+                write!(f, "database is locked by another process: {:?}", path)
+            }
+            Error::BadRow(reason) => write!(f, "{}", reason),
+            Error::Regex { expr, .. } => write!(f, "parsing regex: {:?}", expr),
+            Error::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Lock { source, .. } => Some(source),
+            Error::Regex { source, .. } => Some(source),
+            Error::Locked { .. } | Error::BadRow(_) | Error::Invalid(_) => None,
+        }
+    }
+}
+
+/// Something which went wrong, but not badly enough to give up.
+#[derive(Debug)]
+pub enum Warning {
+    /// a line couldn't be understood, and was skipped; `number` starts at 1
+    BadLine {
+        number: usize,
+        line: String,
+        error: Error,
+    },
+    /// unparseable lines were dropped when rewriting the data file; the original was copied
+    /// to `backup` first
+    Corrupt { lines: usize, backup: PathBuf },
+    /// a secondary data file didn't exist, so was skipped
+    MissingDataFile { path: PathBuf },
+    /// a rewritten data file couldn't be given to the user who should own it
+    Chown {
+        path: PathBuf,
+        uid: u32,
+        source: io::Error,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::BadLine {
+                number,
+                line,
+                error,
+            } => write!(f, "couldn't parse line {} {:?}: {}", number, line, error),
+            Warning::Corrupt { lines, backup } => write!(
+                f,
+                "dropped {} unparseable {} from the data file, the original is at {:?}",
+                lines,
+                if 1 == *lines { "line" } else { "lines" },
+                backup
+            ),
+            Warning::MissingDataFile { path } => {
+                write!(f, "skipping missing data file: {:?}", path)
+            }
+            Warning::Chown { path, uid, source } => {
+                write!(
+                    f,
+                    "couldn't give {:?} back to uid {}: {}",
+                    path, uid, source
+                )
+            }
+        }
+    }
+}
+
+/// A value, plus anything which went wrong on the way to it, but wasn't fatal.
+#[derive(Debug)]
+#[must_use]
+pub struct Outcome<T> {
+    pub value: T,
+    pub warnings: Vec<Warning>,
+}
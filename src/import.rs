@@ -0,0 +1,269 @@
+//! Reading other tools' databases.
+
+use std::str::FromStr;
+
+use crate::store;
+use crate::store::Row;
+use crate::Error;
+use crate::Outcome;
+use crate::Result;
+use crate::Warning;
+
+/// imported weights are scaled so the largest ends up here, so a database
+/// which counts differently doesn't instantly dominate everything
+const MAX_IMPORTED_RANK: f32 = 100.0;
+
+/// autojump and zoxide's list output don't have times; pretend they're this old
+const IMPORTED_AGE: u64 = 60 * 60 * 24 * 7;
+
+/// The formats [`parse`] understands.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// `path|rank|time`, our own format
+    Z,
+    /// `weight\tpath`, from `autojump.txt`
+    Autojump,
+    /// `score path`, from `zoxide query --list --score`
+    Zoxide,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        Ok(match s {
+            "z" => Format::Z,
+            "autojump" => Format::Autojump,
+            "zoxide" => Format::Zoxide,
+            other => {
+                return Err(Error::Invalid(format!(
+                    "unrecognised import format: {:?}",
+                    other
+                )))
+            }
+        })
+    }
+}
+
+impl Format {
+    /// guess the format from the first non-blank line
+    pub fn sniff(data: &[u8]) -> Option<Format> {
+        let line = lines(data).find(|line| !line.trim_ascii().is_empty())?;
+        if line.contains(&b'|') {
+            Some(Format::Z)
+        } else if line.contains(&b'\t') {
+            Some(Format::Autojump)
+        } else {
+            let (score, _) = split_once(line.trim_ascii_start(), b' ')?;
+            let score = std::str::from_utf8(score).ok()?;
+            score.parse::<f32>().ok().map(|_| Format::Zoxide)
+        }
+    }
+}
+
+/// Parse an exported database into rows. Bad lines are skipped, and returned as warnings.
+/// Like the data file, paths needn't be UTF-8.
+///
+/// Ranks from other tools are scaled down, so they don't instantly dominate everything.
+pub fn parse(data: &[u8], format: Format, now: u64) -> Outcome<Vec<Row>> {
+    let mut rows = Vec::new();
+    let mut warnings = Vec::new();
+
+    for (no, line) in lines(data).enumerate() {
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+
+        let row = to_row(line, format, now).and_then(|row| {
+            if row.path.is_absolute() {
+                Ok(row)
+            } else {
+                Err(Error::BadRow(format!(
+                    "path isn't absolute: {:?}",
+                    row.path
+                )))
+            }
+        });
+
+        match row {
+            Ok(row) => rows.push(row),
+            Err(error) => warnings.push(Warning::BadLine {
+                number: no + 1,
+                line: String::from_utf8_lossy(line).into_owned(),
+                error,
+            }),
+        }
+    }
+
+    if Format::Z != format {
+        normalise(&mut rows);
+    }
+
+    Outcome {
+        value: rows,
+        warnings,
+    }
+}
+
+/// split on `\n`, dropping any `\r` before it, like `str::lines`
+fn lines(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split(|&b| b'\n' == b)
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+fn split_once(line: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = line.iter().position(|&b| separator == b)?;
+    Some((&line[..at], &line[at + 1..]))
+}
+
+fn to_row(line: &[u8], format: Format, now: u64) -> Result<Row> {
+    let (rank, path) = match format {
+        Format::Z => return store::to_row(line),
+        Format::Autojump => {
+            split_once(line, b'\t').ok_or_else(|| Error::BadRow("no tab separator".to_string()))?
+        }
+        Format::Zoxide => split_once(line.trim_ascii_start(), b' ')
+            .ok_or_else(|| Error::BadRow("no space separator".to_string()))?,
+    };
+
+    let rank = String::from_utf8_lossy(rank)
+        .trim()
+        .parse::<f32>()
+        .map_err(|e| Error::BadRow(format!("invalid weight: {}", e)))?;
+
+    if !rank.is_finite() || rank <= 0.0 {
+        return Err(Error::BadRow(format!("unusable weight: {:?}", rank)));
+    }
+
+    Ok(Row {
+        path: store::path_from_bytes(path),
+        rank,
+        time: now.saturating_sub(IMPORTED_AGE),
+    })
+}
+
+/// scale ranks down so the biggest is `MAX_IMPORTED_RANK`,
+/// but don't let anything drop below the threshold where it'd be discarded on write
+fn normalise(rows: &mut [Row]) {
+    let max = rows.iter().map(|row| row.rank).fold(0.0, f32::max);
+    let scale = if max > MAX_IMPORTED_RANK {
+        MAX_IMPORTED_RANK / max
+    } else {
+        1.0
+    };
+
+    for row in rows {
+        row.rank = (row.rank * scale).max(1.0);
+    }
+}
+
+/// Merge `imported` into `table`, returning how many rows were already present.
+pub fn merge(table: &mut Vec<Row>, imported: Vec<Row>) -> usize {
+    let mut merged = 0;
+    for row in imported {
+        match table.iter_mut().find(|existing| existing.path == row.path) {
+            Some(existing) => {
+                existing.rank += row.rank;
+                existing.time = existing.time.max(row.time);
+                merged += 1;
+            }
+            None => table.push(row),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::tests::root;
+
+    const NOW: u64 = 1_700_000_000;
+
+    /// move the data's paths under [`root`], so they're absolute on windows too
+    fn rooted(data: &str) -> Vec<u8> {
+        let root = root();
+        let root = root.to_str().expect("test roots are UTF-8");
+        regex::Regex::new(r"(?m)(^|[\t ])/")
+            .unwrap()
+            .replace_all(data, |found: &regex::Captures| {
+                format!("{}{}", &found[1], root)
+            })
+            .into_owned()
+            .into_bytes()
+    }
+
+    #[test]
+    fn sniffing() {
+        assert_eq!(Some(Format::Z), Format::sniff(b"/home/faux|3.5|1700000\n"));
+        assert_eq!(
+            Some(Format::Autojump),
+            Format::sniff(b"\n22.4\t/home/faux\n")
+        );
+        assert_eq!(Some(Format::Zoxide), Format::sniff(b"  12.0 /home/faux\n"));
+        assert_eq!(None, Format::sniff(b"hello world"));
+        assert_eq!(None, Format::sniff(b""));
+    }
+
+    #[test]
+    fn autojump_is_normalised() {
+        let rows = parse(
+            &rooted("1000.0\t/home/faux\n10.0\t/tmp\nbanana\t/usr\n3\trelative/path\n"),
+            Format::Autojump,
+            NOW,
+        );
+        assert_eq!(2, rows.warnings.len());
+        let rows = rows.value;
+        assert_eq!(2, rows.len());
+        assert_eq!(root().join("home/faux"), rows[0].path);
+        assert_eq!(MAX_IMPORTED_RANK, rows[0].rank);
+        assert_eq!(1.0, rows[1].rank);
+        assert_eq!(NOW - IMPORTED_AGE, rows[1].time);
+    }
+
+    #[test]
+    fn zoxide_paths_may_contain_spaces() {
+        let rows = parse(&rooted("  8.5 /home/faux/my stuff\n"), Format::Zoxide, NOW).value;
+        assert_eq!(root().join("home/faux/my stuff"), rows[0].path);
+        assert_eq!(8.5, rows[0].rank);
+    }
+
+    #[test]
+    fn z_is_kept_verbatim() {
+        let rows = parse(&rooted("/home/faux|500|1600000000\n"), Format::Z, NOW).value;
+        assert_eq!(500.0, rows[0].rank);
+        assert_eq!(1_600_000_000, rows[0].time);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_lines() {
+        // a latin-1 path, and a line with a stray byte where the time should be
+        let rows = parse(
+            b"/tmp/caf\xe9|3|100\r\n/srv|1|\xff\n/home/faux|2|100",
+            Format::Z,
+            NOW,
+        );
+        assert_eq!(1, rows.warnings.len());
+        assert_eq!(2, rows.value.len());
+        assert_eq!(
+            b"/tmp/caf\xe9".as_slice(),
+            store::path_bytes(&rows.value[0].path)
+        );
+        assert_eq!(PathBuf::from("/home/faux"), rows.value[1].path);
+
+        assert_eq!(Some(Format::Z), Format::sniff(b"/tmp/caf\xe9|3|100\n"));
+    }
+
+    #[test]
+    fn merging() {
+        let mut table = parse(&rooted("/a|2|100\n/b|1|300\n"), Format::Z, NOW).value;
+        let imported = parse(&rooted("/b|3|200\n/c|1|100\n"), Format::Z, NOW).value;
+        assert_eq!(1, merge(&mut table, imported));
+        assert_eq!(3, table.len());
+        assert_eq!(4.0, table[1].rank);
+        assert_eq!(300, table[1].time);
+    }
+}
@@ -0,0 +1,1021 @@
+//! The database behind `zrs`, a directory jumper based on rupa's z.
+//!
+//! The data file is a list of [`store::Row`]s: directories, how often they've been
+//! visited (their rank), and when they were last visited. [`search`] finds and
+//! orders rows matching a [`Query`], and [`do_add`] records a visit.
+//!
+//! ```
+//! use zrs::{store, Aging, Frecency, Query, Scorer};
+//!
+//! # fn main() -> Result<(), zrs::Error> {
+//! let dir = tempfile::tempdir().unwrap();
+//! let data_file = dir.path().join("z");
+//! let project = dir.path().join("zrs");
+//!
+//! store::update_file(&data_file, &store::WriteOptions::default(), |table| {
+//!     zrs::do_add(table, &project, Aging::default());
+//!     Ok::<_, zrs::Error>(())
+//! })?;
+//!
+//! let now = zrs::unix_time();
+//! let found = zrs::search(&data_file, &Query::new("zrs"), Scorer::Frecent(now, Frecency::Classic))?;
+//! assert_eq!(project, found.value[0].path);
+//! # Ok(())
+//! # }
+//! ```
+
+mod error;
+pub mod import;
+pub mod store;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time;
+
+pub use crate::error::Error;
+pub use crate::error::Outcome;
+pub use crate::error::Warning;
+use crate::store::Row;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A matching row, with its score; higher is better.
+#[derive(Debug)]
+pub struct ScoredRow {
+    pub path: PathBuf,
+    pub rank: f32,
+    pub time: u64,
+    pub score: f32,
+}
+
+/// How to order the matches.
+#[derive(Copy, Clone, Debug)]
+pub enum Scorer {
+    /// by the rank directly
+    Rank,
+    /// by the age directly, relative to the given time
+    Recent(u64),
+    /// a hybrid of the rank and the age (relative to the given time)
+    Frecent(u64, Frecency),
+}
+
+impl Scorer {
+    fn scored(self, row: Row) -> Result<ScoredRow> {
+        let score = match self {
+            Scorer::Rank => row.rank,
+            Scorer::Recent(now) => -(time_delta(now, row.time) as f32),
+            Scorer::Frecent(now, frecency) => frecency.score(row.rank, time_delta(now, row.time)),
+        };
+
+        if !score.is_finite() {
+            return Err(Error::Invalid(format!(
+                "computed non-finite score from {:?}",
+                row
+            )));
+        }
+
+        Ok(ScoredRow {
+            path: row.path,
+            rank: row.rank,
+            time: row.time,
+            score,
+        })
+    }
+}
+
+/// How to combine the rank and the age, for [`Scorer::Frecent`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Frecency {
+    /// z.sh's steps; see [`frecent`]
+    Classic,
+    /// a continuous curve with roughly the same shape; see [`smooth_frecent`]
+    Smooth,
+}
+
+impl FromStr for Frecency {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Frecency> {
+        Ok(match s {
+            "classic" => Frecency::Classic,
+            "smooth" => Frecency::Smooth,
+            other => {
+                return Err(Error::Invalid(format!(
+                    "unrecognised frecency: {:?}",
+                    other
+                )))
+            }
+        })
+    }
+}
+
+impl Frecency {
+    /// Combine a rank with the time since the last visit, `dx`, in seconds.
+    pub fn score(self, rank: f32, dx: u64) -> f32 {
+        match self {
+            Frecency::Classic => frecent(rank, dx),
+            Frecency::Smooth => smooth_frecent(rank, dx),
+        }
+    }
+}
+
+const HOUR: u64 = 3600;
+const DAY: u64 = HOUR * 24;
+const WEEK: u64 = DAY * 7;
+
+/// Combine a rank with the time since the last visit, `dx`, in seconds, like z.sh:
+/// ×4 within the hour, ×2 within the day, ÷2 within the week, then ÷4.
+pub fn frecent(rank: f32, dx: u64) -> f32 {
+    // relate frequency and time
+    if dx < HOUR {
+        rank * 4.0
+    } else if dx < DAY {
+        rank * 2.0
+    } else if dx < WEEK {
+        rank / 2.0
+    } else {
+        rank / 4.0
+    }
+}
+
+/// Combine a rank with the time since the last visit, `dx`, in seconds, without
+/// [`frecent`]'s sudden jumps: `rank × (1/4 + 15/4 × 2^(-dx / 1 day))`.
+///
+/// Like the steps, this starts at ×4, is about ×2 after a day, and settles at ÷4.
+pub fn smooth_frecent(rank: f32, dx: u64) -> f32 {
+    let days = dx as f64 / DAY as f64;
+    (f64::from(rank) * (0.25 + 3.75 * (-days).exp2())) as f32
+}
+
+/// how the expression should treat upper and lower case
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CaseMode {
+    /// case sensitive, unless that finds nothing, then insensitive
+    Smart,
+    Sensitive,
+    Insensitive,
+}
+
+impl Default for CaseMode {
+    /// smart, except where the filesystem doesn't care about case anyway
+    fn default() -> CaseMode {
+        if cfg!(windows) {
+            CaseMode::Insensitive
+        } else {
+            CaseMode::Smart
+        }
+    }
+}
+
+/// What to search for.
+#[derive(Clone, Debug)]
+pub struct Query {
+    /// a regex, which must match somewhere in the path
+    pub expr: String,
+    /// more regexes, which must also each match somewhere in the path, in any order
+    pub unordered: Vec<String>,
+    /// a regex for the last term typed; rows where this matches in the final
+    /// component are preferred
+    pub last_term: Option<String>,
+    pub case: CaseMode,
+    /// where the search is happening; rows below here are preferred, by `cwd_boost`
+    pub cwd: Option<PathBuf>,
+    pub cwd_boost: f32,
+}
+
+impl Query {
+    /// Match `expr` anywhere in the path, using the default case handling.
+    pub fn new<S: Into<String>>(expr: S) -> Query {
+        Query {
+            expr: expr.into(),
+            unordered: Vec::new(),
+            last_term: None,
+            case: CaseMode::default(),
+            cwd: None,
+            cwd_boost: DEFAULT_CWD_BOOST,
+        }
+    }
+}
+
+/// how much more likely a row is when it's below the current directory, by default
+pub const DEFAULT_CWD_BOOST: f32 = 3.;
+
+/// for a regex character class
+#[cfg(windows)]
+const SEPARATORS: &str = r"/\\";
+#[cfg(not(windows))]
+const SEPARATORS: &str = "/";
+
+/// how much more likely a row is when the last term matches its final component
+const LAST_TERM_BOOST: f32 = 10.;
+
+/// Find the rows in the data file matching the query, worst first.
+///
+/// Lines of the data file which can't be parsed are returned as warnings.
+pub fn search<P: AsRef<Path>>(
+    data_file: P,
+    query: &Query,
+    mode: Scorer,
+) -> Result<Outcome<Vec<ScoredRow>>> {
+    search_files(&[data_file], query, mode)
+}
+
+/// Like [`search`], over the rows of all of the data files; see [`store::parse_files`].
+pub fn search_files<P: AsRef<Path>>(
+    data_files: &[P],
+    query: &Query,
+    mode: Scorer,
+) -> Result<Outcome<Vec<ScoredRow>>> {
+    let table = store::parse_files(data_files)?;
+    let found = matching(&table.value, &query.expr, &query.unordered, query.case)?;
+
+    // we own the table, so the matches can be moved out, instead of copied
+    let mut found = found.into_iter().peekable();
+    let matches = table
+        .value
+        .into_iter()
+        .enumerate()
+        .filter(|&(i, _)| found.next_if_eq(&i).is_some())
+        .map(|(_, row)| row)
+        .collect();
+
+    Ok(Outcome {
+        value: score(matches, query, mode)?,
+        warnings: table.warnings,
+    })
+}
+
+/// Find the rows in the table matching the query, worst first.
+pub fn search_table(table: &[Row], query: &Query, mode: Scorer) -> Result<Vec<ScoredRow>> {
+    let matches = matching(table, &query.expr, &query.unordered, query.case)?
+        .into_iter()
+        .map(|i| table[i].clone())
+        .collect();
+
+    score(matches, query, mode)
+}
+
+fn score(matches: Vec<Row>, query: &Query, mode: Scorer) -> Result<Vec<ScoredRow>> {
+    let case = query.case;
+    let mut scored = matches
+        .into_iter()
+        .map(|row| mode.scored(row))
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some(term) = &query.last_term {
+        // like z.sh, `z foo bar` wants to end up in a `bar`,
+        // not in some deeper directory which happens to be below a `bar`
+        let tail = regex::RegexBuilder::new(&format!("(?:{})[^{}]*$", term, SEPARATORS))
+            .case_insensitive(CaseMode::Sensitive != case)
+            .build()
+            .map_err(|source| Error::Regex {
+                expr: term.to_string(),
+                source,
+            })?;
+
+        for row in &mut scored {
+            if tail.is_match(&row.path.to_string_lossy()) {
+                row.score = boost(row.score, LAST_TERM_BOOST);
+            }
+        }
+    }
+
+    if let Some(cwd) = &query.cwd {
+        // `z src` from inside one project probably means that project's `src`
+        for row in &mut scored {
+            if row.path != *cwd && row.path.starts_with(cwd) {
+                row.score = boost(row.score, query.cwd_boost);
+            }
+        }
+    }
+
+    if let Some(prefix) = common_prefix(&scored) {
+        if let Some(row) = scored.iter_mut().find(|row| prefix == row.path) {
+            // if all of the matches have a common prefix,
+            // and that common prefix is in the list,
+            // then it is *much* more likely to be our guy.
+            row.score *= 100.;
+        }
+    }
+
+    scored.sort_by(compare_score);
+
+    Ok(scored)
+}
+
+/// `Scorer::Recent` scores are negative, where making them bigger would make them worse
+fn boost(score: f32, factor: f32) -> f32 {
+    if score < 0. {
+        score / factor
+    } else {
+        score * factor
+    }
+}
+
+/// Add a term to an expression; terms must match in order, with anything in between.
+/// `literal` terms are escaped, instead of being treated as regexes.
+///
+/// For terms which can match in any order, see [`Query::unordered`].
+pub fn push_term(expr: &mut String, term: &str, literal: bool) {
+    if !expr.is_empty() {
+        expr.push_str(".*");
+    }
+    if literal {
+        expr.push_str(&regex::escape(term));
+    } else {
+        expr.push_str(term);
+    }
+}
+
+/// The indexes of the rows where `expr`, and every one of `unordered`, match, in order.
+fn matching(table: &[Row], expr: &str, unordered: &[String], case: CaseMode) -> Result<Vec<usize>> {
+    let regexes = |insensitive| {
+        std::iter::once(expr)
+            .chain(unordered.iter().map(String::as_str))
+            .map(|expr| {
+                regex::RegexBuilder::new(expr)
+                    .case_insensitive(insensitive)
+                    .build()
+                    .map_err(|source| Error::Regex {
+                        expr: expr.to_string(),
+                        source,
+                    })
+            })
+            .collect::<Result<Vec<_>>>()
+    };
+    let all_match =
+        |regexes: &[regex::Regex], path: &str| regexes.iter().all(|regex| regex.is_match(path));
+
+    let first = regexes(CaseMode::Insensitive == case)?;
+    // only smart mode needs the fallback; build it up front, so there's only one pass
+    let fallback = match case {
+        CaseMode::Smart => Some(regexes(true)?),
+        CaseMode::Sensitive | CaseMode::Insensitive => None,
+    };
+
+    let mut matches = Vec::new();
+    let mut fallback_matches = Vec::new();
+
+    for (i, row) in table.iter().enumerate() {
+        let path = row.path.to_string_lossy();
+        if all_match(&first, &path) {
+            matches.push(i);
+            continue;
+        }
+
+        // the fallback is for the terms as a whole; one term matching with the right case
+        // isn't enough to stop the others from trying without it. Once anything has
+        // matched properly, the fallback is never going to be used.
+        if let Some(fallback) = &fallback {
+            if matches.is_empty() && all_match(fallback, &path) {
+                fallback_matches.push(i);
+            }
+        }
+    }
+
+    Ok(if matches.is_empty() {
+        fallback_matches
+    } else {
+        matches
+    })
+}
+
+fn common_prefix(rows: &[ScoredRow]) -> Option<PathBuf> {
+    if rows.len() <= 1 {
+        return None;
+    }
+
+    let mut rows = rows.iter();
+    let mut shortest = rows.next().expect("len > 1").path.to_path_buf();
+
+    for part in rows {
+        let part = part.path.to_path_buf();
+        while !part.starts_with(&shortest) {
+            if !shortest.pop() || shortest.parent().is_none() {
+                return None;
+            }
+        }
+    }
+
+    Some(shortest)
+}
+
+fn total_rank(table: &[Row]) -> f32 {
+    table.iter().map(|line| line.rank).sum()
+}
+
+/// once the ranks add up to more than `max_score`, every rank is multiplied by `decay`
+#[derive(Copy, Clone, Debug)]
+pub struct Aging {
+    max_score: f32,
+    decay: f32,
+}
+
+impl Default for Aging {
+    fn default() -> Aging {
+        Aging {
+            max_score: 9000.0,
+            decay: 0.99,
+        }
+    }
+}
+
+impl Aging {
+    /// `max_score` must be positive, and `decay` must be between 0 and 1.
+    pub fn new(max_score: f32, decay: f32) -> Result<Aging> {
+        if !(max_score.is_finite() && max_score > 0.0) {
+            return Err(Error::Invalid(format!(
+                "max score must be positive, not {}",
+                max_score
+            )));
+        }
+        if !(decay > 0.0 && decay < 1.0) {
+            return Err(Error::Invalid(format!(
+                "decay must be between 0 and 1 (exclusive), not {}",
+                decay
+            )));
+        }
+        Ok(Aging { max_score, decay })
+    }
+
+    pub fn max_score(self) -> f32 {
+        self.max_score
+    }
+
+    pub fn decay(self) -> f32 {
+        self.decay
+    }
+
+    /// Decay the table, if it's got too big, dropping rows which have become insignificant.
+    pub fn apply(self, table: &mut Vec<Row>) {
+        if total_rank(table) <= self.max_score {
+            return;
+        }
+
+        for line in table.iter_mut() {
+            line.rank *= self.decay;
+        }
+
+        // these would be dropped on write anyway, but be explicit about it
+        table.retain(|line| line.rank >= store::MIN_RANK);
+    }
+}
+
+/// Like z.sh, visits to `$HOME` and `/` aren't recorded: every new terminal starts in one
+/// of them, so they'd dominate everything else. Relative paths are no use later.
+pub fn worth_adding(path: &Path) -> bool {
+    // the root is the only absolute path without a parent, even on windows
+    path.is_absolute()
+        && path.parent().is_some()
+        && dirs::home_dir().is_none_or(|home| path != home)
+}
+
+/// Record a visit to `what`, then age the table. Returns false, and does nothing,
+/// if `what` isn't [`worth_adding`].
+pub fn do_add<Q: AsRef<Path>>(table: &mut Vec<Row>, what: Q, aging: Aging) -> bool {
+    do_add_weighted(table, what, 1.0, aging)
+}
+
+/// Like [`do_add`], but the visit counts `weight` times. The weight can be fractional, but
+/// should be positive; a new row with a weight below [`store::MIN_RANK`] won't be written.
+pub fn do_add_weighted<Q: AsRef<Path>>(
+    table: &mut Vec<Row>,
+    what: Q,
+    weight: f32,
+    aging: Aging,
+) -> bool {
+    let what = what.as_ref();
+    if !worth_adding(what) {
+        return false;
+    }
+
+    let found = match table.iter_mut().find(|row| row.path == what) {
+        Some(row) => {
+            row.rank += weight;
+            row.time = unix_time();
+            true
+        }
+        None => false,
+    };
+
+    if !found {
+        table.push(Row {
+            path: what.to_path_buf(),
+            rank: weight,
+            time: unix_time(),
+        });
+    }
+
+    // even a huge weight decays, eventually, like everything else
+    aging.apply(table);
+    true
+}
+
+/// Forget about `what`. Returns whether it was present.
+pub fn do_remove<Q: AsRef<Path>>(table: &mut Vec<Row>, what: Q) -> bool {
+    let what = what.as_ref();
+    let start = table.len();
+    table.retain(|row| row.path != what);
+    start != table.len()
+}
+
+/// Rewrite every path with `key`, then merge rows which now have the same path,
+/// summing the ranks and keeping the latest time. Returns the number of rows merged away.
+pub fn merge_duplicates<F>(table: &mut Vec<Row>, key: F) -> usize
+where
+    F: Fn(&Path) -> PathBuf,
+{
+    let start = table.len();
+    let mut merged: Vec<Row> = Vec::with_capacity(start);
+    let mut seen: HashMap<PathBuf, usize> = HashMap::with_capacity(start);
+
+    for mut row in table.drain(..) {
+        row.path = key(&row.path);
+        match seen.get(&row.path) {
+            Some(&existing) => {
+                let existing = &mut merged[existing];
+                existing.rank += row.rank;
+                existing.time = existing.time.max(row.time);
+            }
+            None => {
+                seen.insert(row.path.clone(), merged.len());
+                merged.push(row);
+            }
+        }
+    }
+
+    *table = merged;
+    start - table.len()
+}
+
+/// `/home/faux/` to `/home/faux`, so they merge; `Path`'s `==` already ignores the difference,
+/// but the stored bytes don't. Repeated separators, and `.`s, are also dropped.
+pub fn trim_trailing_slashes(path: &Path) -> PathBuf {
+    path.components().collect()
+}
+
+fn compare_score(left: &ScoredRow, right: &ScoredRow) -> cmp::Ordering {
+    left.score
+        .partial_cmp(&right.score)
+        .expect("no NaNs in scoring")
+}
+
+/// The current time, in seconds since the unix epoch, as stored in [`store::Row::time`].
+pub fn unix_time() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Seconds between `then` and `now`; zero if `then` is in the future.
+pub fn time_delta(now: u64, then: u64) -> u64 {
+    now.saturating_sub(then)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    use super::ScoredRow;
+    use crate::store::Row;
+
+    #[test]
+    fn pathbuf_pop() {
+        let mut p = PathBuf::from("/home/faux");
+        assert!(p.pop());
+        assert_eq!(PathBuf::from("/home"), p);
+        assert!(p.pop());
+        assert_eq!(PathBuf::from("/"), p);
+        // a path for / has no parent, but `pop()` succeeded
+        assert_eq!(None, p.parent());
+        assert!(!p.pop());
+
+        // further popping doesn't remove anything
+        assert_eq!(PathBuf::from("/"), p);
+    }
+
+    #[test]
+    fn common() {
+        use super::common_prefix;
+        assert_eq!(None, common_prefix(&[]));
+        assert_eq!(None, common_prefix(&[s("/home")]));
+        assert_eq!(None, common_prefix(&[s("/home"), s("/etc")]));
+        assert_eq!(
+            Some(PathBuf::from("/home")),
+            common_prefix(&[s("/home/faux"), s("/home/john")])
+        );
+
+        assert_eq!(
+            Some(PathBuf::from("/home")),
+            common_prefix(&[
+                s("/home/faux"),
+                s("/home/alex/public_html"),
+                s("/home/john"),
+                s("/home/alex")
+            ])
+        );
+    }
+
+    #[test]
+    fn merging_duplicates() {
+        use super::merge_duplicates;
+
+        let mut table = vec![
+            row("/home/faux/work", 2., 100),
+            row("/mnt/ssd/work", 3., 50),
+            row("/tmp", 1., 10),
+            row("/home/faux/work/zrs", 1., 200),
+        ];
+
+        let merged = merge_duplicates(&mut table, |path| match path.strip_prefix("/home/faux") {
+            Ok(rest) => Path::new("/mnt/ssd").join(rest),
+            Err(_) => path.to_path_buf(),
+        });
+
+        assert_eq!(1, merged);
+        assert_eq!(3, table.len());
+        assert_eq!(PathBuf::from("/mnt/ssd/work"), table[0].path);
+        assert_eq!(5., table[0].rank);
+        assert_eq!(100, table[0].time);
+        assert_eq!(PathBuf::from("/tmp"), table[1].path);
+        assert_eq!(PathBuf::from("/mnt/ssd/work/zrs"), table[2].path);
+    }
+
+    #[test]
+    fn trailing_slashes() {
+        use super::merge_duplicates;
+        use super::trim_trailing_slashes;
+
+        // compared as paths: on windows, the separators come back as `\`
+        assert_eq!(
+            Path::new("/home/faux"),
+            trim_trailing_slashes(Path::new("/home/faux//"))
+        );
+        assert_eq!(Path::new("/"), trim_trailing_slashes(Path::new("/")));
+        // on windows, that's the start of a UNC path
+        if cfg!(unix) {
+            assert_eq!(Path::new("/"), trim_trailing_slashes(Path::new("//")));
+        }
+
+        let mut table = vec![row("/home/faux/", 1., 30), row("/home/faux", 2., 20)];
+        assert_eq!(1, merge_duplicates(&mut table, trim_trailing_slashes));
+        assert_eq!(1, table.len());
+        assert_eq!(Path::new("/home/faux"), table[0].path);
+        assert_eq!(3., table[0].rank);
+        assert_eq!(30, table[0].time);
+    }
+
+    #[test]
+    fn aging() {
+        use super::do_add;
+        use super::Aging;
+
+        // under the threshold: nothing changes
+        let mut table = vec![row("/a", 5., 0), row("/b", 1., 0)];
+        Aging::new(10., 0.5).unwrap().apply(&mut table);
+        assert_eq!(vec![5., 1.], ranks(&table));
+
+        // over the threshold: everything decays, and tiny rows go away
+        let mut table = vec![row("/a", 10., 0), row("/b", 1.5, 0), row("/c", 1., 0)];
+        Aging::new(10., 0.5).unwrap().apply(&mut table);
+        assert_eq!(vec![5.], ranks(&table));
+
+        // the default matches the historical behaviour
+        let mut table = vec![row("/a", 9000., 0)];
+        do_add(&mut table, root().join("b"), Aging::default());
+        assert_eq!(vec![9000. * 0.99, 0.99], ranks(&table));
+
+        assert!(Aging::new(0., 0.5).is_err());
+        assert!(Aging::new(-5., 0.5).is_err());
+        assert!(Aging::new(10., 0.).is_err());
+        assert!(Aging::new(10., 1.).is_err());
+    }
+
+    #[test]
+    fn weighted_adds() {
+        use super::do_add_weighted;
+        use super::Aging;
+
+        let mut table = Vec::new();
+        let a = root().join("a");
+        assert!(do_add_weighted(&mut table, &a, 50., Aging::default()));
+        assert!(do_add_weighted(&mut table, &a, 0.5, Aging::default()));
+        assert_eq!(vec![50.5], ranks(&table));
+
+        // still ages the table, including the row which pushed it over
+        let aging = Aging::new(100., 0.5).unwrap();
+        assert!(do_add_weighted(&mut table, root().join("b"), 60., aging));
+        assert_eq!(vec![50.5 * 0.5, 30.], ranks(&table));
+    }
+
+    #[test]
+    fn frecency_curves() {
+        use super::Frecency;
+        use super::DAY;
+        use super::HOUR;
+        use super::WEEK;
+
+        let scores = |frecency: Frecency| -> Vec<String> {
+            [
+                0,
+                HOUR - 1,
+                HOUR + 1,
+                DAY - 1,
+                DAY + 1,
+                WEEK - 1,
+                WEEK + 1,
+                52 * WEEK,
+            ]
+            .iter()
+            .map(|&dx| format!("{:.3}", frecency.score(10., dx)))
+            .collect()
+        };
+
+        assert_eq!(
+            vec!["40.000", "40.000", "20.000", "20.000", "5.000", "5.000", "2.500", "2.500"],
+            scores(Frecency::Classic)
+        );
+        assert_eq!(
+            vec!["40.000", "38.933", "38.932", "21.250", "21.250", "2.793", "2.793", "2.500"],
+            scores(Frecency::Smooth)
+        );
+
+        assert_eq!(Frecency::Smooth, "smooth".parse().unwrap());
+        assert!("jagged".parse::<Frecency>().is_err());
+    }
+
+    #[test]
+    fn boring_adds() {
+        use super::do_add;
+        use super::Aging;
+
+        let home = dirs::home_dir().expect("tests need a home");
+        let root = root();
+        let mut table = vec![row("/a", 9000., 0)];
+
+        for path in [
+            home.as_path(),
+            root.as_path(),
+            Path::new("a/b"),
+            Path::new(""),
+        ] {
+            assert!(!do_add(&mut table, path, Aging::default()), "{:?}", path);
+            assert_eq!(vec![9000.], ranks(&table));
+        }
+
+        assert!(do_add(&mut table, home.join("code"), Aging::default()));
+        assert_eq!(2, table.len());
+    }
+
+    fn ranks(table: &[Row]) -> Vec<f32> {
+        table.iter().map(|row| row.rank).collect()
+    }
+
+    /// `/`, or `C:\` or so on windows, where `/a` isn't absolute, so wouldn't be added
+    pub(crate) fn root() -> PathBuf {
+        let temp = std::env::temp_dir();
+        temp.ancestors().last().expect("non-empty").to_path_buf()
+    }
+
+    #[test]
+    fn case_modes() {
+        use super::matching;
+        use super::CaseMode;
+
+        let table = vec![
+            row("/home/faux/Documents", 1., 0),
+            row("/home/faux/proj/docs", 1., 0),
+        ];
+        let paths = |expr, case| -> Vec<PathBuf> {
+            matching(&table, expr, &[], case)
+                .unwrap()
+                .into_iter()
+                .map(|i| table[i].path.clone())
+                .collect()
+        };
+
+        assert_eq!(vec![table[1].path.clone()], paths("docs", CaseMode::Smart));
+        assert_eq!(vec![table[0].path.clone()], paths("Doc", CaseMode::Smart));
+        assert_eq!(2, paths("DOC", CaseMode::Smart).len());
+        assert_eq!(2, paths("doc", CaseMode::Insensitive).len());
+        assert!(paths("DOC", CaseMode::Sensitive).is_empty());
+        assert_eq!(1, paths("doc", CaseMode::Sensitive).len());
+    }
+
+    #[test]
+    fn literal_terms() {
+        use super::matching;
+        use super::push_term;
+        use super::CaseMode;
+
+        let table = vec![
+            row("/home/faux/c++/proj", 1., 0),
+            row("/home/faux/cc/proj", 1., 0),
+            row("/home/faux/foo(bar)", 1., 0),
+            row("/home/faux/[x]/y", 1., 0),
+        ];
+
+        let find = |terms: &[&str], literal| {
+            let mut expr = String::new();
+            for term in terms {
+                push_term(&mut expr, term, literal);
+            }
+            matching(&table, &expr, &[], CaseMode::Smart).map(|found| {
+                found
+                    .into_iter()
+                    .map(|i| table[i].path.clone())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(
+            vec![PathBuf::from("/home/faux/c++/proj")],
+            find(&["c++", "proj"], true).unwrap()
+        );
+        assert!(find(&["foo(bar"], false).is_err());
+        assert_eq!(
+            vec![PathBuf::from("/home/faux/foo(bar)")],
+            find(&["foo(bar"], true).unwrap()
+        );
+
+        assert_eq!(
+            vec![PathBuf::from("/home/faux/foo(bar)")],
+            find(&["foo(bar)"], true).unwrap()
+        );
+        // as a regex, the parens are a group, which doesn't match the literal parens
+        assert!(find(&["foo(bar)"], false).unwrap().is_empty());
+
+        assert_eq!(
+            vec![PathBuf::from("/home/faux/[x]/y")],
+            find(&["[x]"], true).unwrap()
+        );
+        // as a regex, that's a character class, which matches everything with an x
+        assert_eq!(4, find(&["[x]"], false).unwrap().len());
+    }
+
+    #[test]
+    fn any_order() {
+        use super::push_term;
+        use super::search_table;
+        use super::CaseMode;
+        use super::Query;
+        use super::Scorer;
+
+        let table = vec![
+            row("/home/faux/foo-service/bar", 1., 0),
+            row("/home/faux/bar-service/foo", 1., 0),
+            row("/home/faux/foo-service/baz", 1., 0),
+        ];
+
+        let find = |terms: &[&str], any_order: bool, case| {
+            let mut query = Query {
+                case,
+                ..Query::new("")
+            };
+            for term in terms {
+                if any_order {
+                    query.unordered.push(term.to_string());
+                } else {
+                    push_term(&mut query.expr, term, false);
+                }
+            }
+            let mut paths: Vec<_> = search_table(&table, &query, Scorer::Rank)
+                .unwrap()
+                .into_iter()
+                .map(|row| row.path)
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        // the default is still ordered
+        assert_eq!(
+            vec![PathBuf::from("/home/faux/foo-service/bar")],
+            find(&["foo", "bar"], false, CaseMode::Smart)
+        );
+        assert_eq!(
+            vec![PathBuf::from("/home/faux/bar-service/foo")],
+            find(&["bar", "foo"], false, CaseMode::Smart)
+        );
+
+        // either way around finds both
+        let both = vec![
+            PathBuf::from("/home/faux/bar-service/foo"),
+            PathBuf::from("/home/faux/foo-service/bar"),
+        ];
+        assert_eq!(both, find(&["foo", "bar"], true, CaseMode::Smart));
+        assert_eq!(both, find(&["bar", "foo"], true, CaseMode::Smart));
+
+        // every term has to match
+        assert!(find(&["foo", "bar", "baz"], true, CaseMode::Smart).is_empty());
+
+        // "foo" matches with its case, but that doesn't stop the set from falling back
+        assert_eq!(both, find(&["foo", "BAR"], true, CaseMode::Smart));
+        assert!(find(&["foo", "BAR"], true, CaseMode::Sensitive).is_empty());
+    }
+
+    #[test]
+    fn last_term_preferred() {
+        use super::score;
+        use super::CaseMode;
+        use super::Query;
+        use super::Scorer;
+
+        let table = vec![
+            row("/home/faux/code/zrs", 1., 0),
+            row("/home/faux/code/zrs/target/z-stuff/old", 5., 0),
+            row("/home/faux/code/old-zrs/src", 5., 0),
+        ];
+
+        let best = |last_term: Option<&str>| {
+            let query = Query {
+                last_term: last_term.map(str::to_string),
+                case: CaseMode::Smart,
+                ..Query::new("")
+            };
+            score(table.clone(), &query, Scorer::Rank)
+                .unwrap()
+                .pop()
+                .unwrap()
+        };
+
+        // without the preference, the deep directory wins on rank
+        assert_eq!(5., best(None).score);
+
+        let row = best(Some("zrs"));
+        assert_eq!(PathBuf::from("/home/faux/code/zrs"), row.path);
+        assert_eq!(10., row.score);
+
+        // old-zrs/src has an "old", but not in its final component
+        let row = best(Some("old"));
+        assert_eq!(
+            PathBuf::from("/home/faux/code/zrs/target/z-stuff/old"),
+            row.path
+        );
+        assert_eq!(50., row.score);
+    }
+
+    #[test]
+    fn cwd_preferred() {
+        use super::search_table;
+        use super::Query;
+        use super::Scorer;
+
+        let table = vec![
+            row("/home/faux/work/clientA", 1., 0),
+            row("/home/faux/work/clientA/src", 1., 0),
+            row("/home/faux/work/clientB/src", 2., 0),
+        ];
+
+        let best = |cwd: Option<&str>, cwd_boost| {
+            let query = Query {
+                cwd: cwd.map(PathBuf::from),
+                cwd_boost,
+                ..Query::new("src")
+            };
+            search_table(&table, &query, Scorer::Rank)
+                .unwrap()
+                .pop()
+                .unwrap()
+        };
+
+        // without a cwd, the lifetime rank wins
+        let row = best(None, 3.);
+        assert_eq!(PathBuf::from("/home/faux/work/clientB/src"), row.path);
+
+        let row = best(Some("/home/faux/work/clientA"), 3.);
+        assert_eq!(PathBuf::from("/home/faux/work/clientA/src"), row.path);
+        // the boost is visible in the score
+        assert_eq!(3., row.score);
+
+        // not enough to overcome the rank
+        let row = best(Some("/home/faux/work/clientA"), 1.5);
+        assert_eq!(PathBuf::from("/home/faux/work/clientB/src"), row.path);
+
+        // component-wise, so clientAB isn't below clientA
+        let row = best(Some("/home/faux/work/client"), 3.);
+        assert_eq!(PathBuf::from("/home/faux/work/clientB/src"), row.path);
+    }
+
+    fn row<P: AsRef<Path>>(path: P, rank: f32, time: u64) -> Row {
+        Row {
+            path: path.as_ref().to_path_buf(),
+            rank,
+            time,
+        }
+    }
+
+    fn s<P: AsRef<Path>>(path: P) -> ScoredRow {
+        ScoredRow {
+            path: path.as_ref().to_path_buf(),
+            rank: 0.,
+            time: 0,
+            score: 0.,
+        }
+    }
+}
@@ -1,14 +1,20 @@
-mod store;
-
-use std::cmp;
+use std::collections::HashSet;
 use std::env;
 use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::fs;
+use std::io;
+use std::io::Read;
 use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+use std::path;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process;
-use std::time;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::ensure;
@@ -16,172 +22,35 @@ use anyhow::Context;
 use anyhow::Result;
 use clap::ArgGroup;
 use clap::{Arg, ArgAction};
-use nix::unistd;
-
-use crate::store::Row;
+use clap_complete::Shell;
+#[cfg(unix)]
+use nix::unistd::User;
+
+use zrs::import;
+use zrs::store;
+use zrs::store::Row;
+use zrs::unix_time;
+use zrs::Aging;
+use zrs::CaseMode;
+use zrs::Frecency;
+use zrs::Query;
+use zrs::ScoredRow;
+use zrs::Scorer;
+use zrs::Warning;
 
 const HELPER_SCRIPT: &[u8] = include_bytes!("../z.sh");
-
-#[derive(Debug)]
-struct ScoredRow {
-    path: PathBuf,
-    score: f32,
-}
-
-#[derive(Copy, Clone)]
-enum Scorer {
-    Rank,
-    Recent(u64),
-    Frecent(u64),
-}
-
-impl Scorer {
-    fn scored(self, row: Row) -> Result<ScoredRow> {
-        let score = match self {
-            Scorer::Rank => row.rank,
-            Scorer::Recent(now) => -(time_delta(now, row.time) as f32),
-            Scorer::Frecent(now) => frecent(row.rank, time_delta(now, row.time)),
-        };
-
-        ensure!(
-            score.is_finite(),
-            "computed non-finite score from {:?}",
-            row
-        );
-
-        Ok(ScoredRow {
-            path: row.path,
-            score,
-        })
-    }
-}
-
-fn frecent(rank: f32, dx: u64) -> f32 {
-    const HOUR: u64 = 3600;
-    const DAY: u64 = HOUR * 24;
-    const WEEK: u64 = DAY * 7;
-
-    // relate frequency and time
-    if dx < HOUR {
-        rank * 4.0
-    } else if dx < DAY {
-        rank * 2.0
-    } else if dx < WEEK {
-        rank / 2.0
-    } else {
-        rank / 4.0
-    }
-}
-
-fn search<P: AsRef<Path>>(data_file: P, expr: &str, mode: Scorer) -> Result<Vec<ScoredRow>> {
-    let table =
-        store::parse(store::open_data_file(data_file)?).with_context(|| anyhow!("parsing"))?;
-
-    let mut matches: Vec<_> = {
-        let sensitive = regex::RegexBuilder::new(expr)
-            .case_insensitive(false)
-            .build()
-            .with_context(|| anyhow!("parsing regex: {:?}", expr))?;
-
-        table
-            .iter()
-            .filter(|row| sensitive.is_match(&row.path.to_string_lossy()))
-            .cloned()
-            .collect()
-    };
-
-    if matches.is_empty() {
-        let insensitive = regex::RegexBuilder::new(expr)
-            .case_insensitive(true)
-            .build()?;
-
-        matches = table
-            .into_iter()
-            .filter(|row| insensitive.is_match(&row.path.to_string_lossy()))
-            .collect();
-    }
-
-    let mut scored = matches
-        .into_iter()
-        .map(|row| mode.scored(row))
-        .collect::<Result<Vec<_>>>()?;
-
-    if let Some(prefix) = common_prefix(&scored) {
-        if let Some(row) = scored.iter_mut().find(|row| prefix == row.path) {
-            // if all of the matches have a common prefix,
-            // and that common prefix is in the list,
-            // then it is *much* more likely to be our guy.
-            row.score *= 100.;
-        }
-    }
-
-    scored.sort_by(compare_score);
-
-    Ok(scored)
-}
-
-fn common_prefix(rows: &[ScoredRow]) -> Option<PathBuf> {
-    if rows.len() <= 1 {
-        return None;
-    }
-
-    let mut rows = rows.iter();
-    let mut shortest = rows.next().expect("len > 1").path.to_path_buf();
-
-    for part in rows {
-        let part = part.path.to_path_buf();
-        while !part.starts_with(&shortest) {
-            if !shortest.pop() || shortest.parent().is_none() {
-                return None;
-            }
-        }
-    }
-
-    Some(shortest)
-}
-
-fn total_rank(table: &[Row]) -> f32 {
-    table.iter().map(|line| line.rank).sum()
-}
-
-fn do_add<Q: AsRef<Path>>(table: &mut Vec<Row>, what: Q) -> Result<()> {
-    let what = what.as_ref();
-
-    let found = match table.iter_mut().find(|row| row.path == what) {
-        Some(row) => {
-            row.rank += 1.0;
-            row.time = unix_time();
-            true
-        }
-        None => false,
-    };
-
-    if !found {
-        table.push(Row {
-            path: what.to_path_buf(),
-            rank: 1.0,
-            time: unix_time(),
-        });
-    }
-
-    // aging
-    if total_rank(table) > 9000.0 {
-        for line in table {
-            line.rank *= 0.99;
-        }
-    }
-
-    Ok(())
-}
-
-fn run() -> Result<Return> {
-    let data_file = match env::var_os("_Z_DATA") {
-        Some(x) => PathBuf::from(&x),
-        None => home_dir()?.join(".z"),
-    };
-
-    let matches = clap::command!()
-        .group(ArgGroup::new("sort-mode").args(&["rank", "recent", "frecent"]))
+const FISH_HELPER_SCRIPT: &[u8] = include_bytes!("../z.fish");
+const POWERSHELL_HELPER_SCRIPT: &[u8] = include_bytes!("../z.ps1");
+
+fn build_cli() -> clap::Command {
+    clap::command!()
+        .group(ArgGroup::new("sort-mode").args(["rank", "recent", "frecent"]))
+        .group(ArgGroup::new("case-mode").args(["case-sensitive", "ignore-case"]))
+        .group(
+            ArgGroup::new("add-mode")
+                .args(["add", "add-blocking", "add-from-stdin", "add-background"])
+                .multiple(true),
+        )
         .arg(
             Arg::new("frecent")
                 .short('f')
@@ -203,6 +72,43 @@ fn run() -> Result<Return> {
                 .action(ArgAction::SetTrue)
                 .help("sort by the match's age directly (ignore the rank component)"),
         )
+        .arg(
+            Arg::new("frecency")
+                .long("frecency")
+                .value_name("CURVE")
+                .value_parser(["classic", "smooth"])
+                .help("how --frecent combines rank and age (default: _Z_FRECENCY or classic). \
+                    classic: rank ×4 within an hour, ×2 within a day, ÷2 within a week, else ÷4. \
+                    smooth: rank × (1/4 + 15/4 × 2^(-age in days))"),
+        )
+        .arg(
+            Arg::new("case-sensitive")
+                .short('S')
+                .long("case-sensitive")
+                .action(ArgAction::SetTrue)
+                .help("only match with the same case (default: try this, then ignore case)"),
+        )
+        .arg(
+            Arg::new("ignore-case")
+                .short('I')
+                .long("ignore-case")
+                .action(ArgAction::SetTrue)
+                .help("match regardless of case"),
+        )
+        .arg(
+            Arg::new("literal")
+                .short('L')
+                .long("literal")
+                .action(ArgAction::SetTrue)
+                .help("treat terms as plain text, not regexes (default: _Z_LITERAL)"),
+        )
+        .arg(
+            Arg::new("any-order")
+                .short('a')
+                .long("any-order")
+                .action(ArgAction::SetTrue)
+                .help("terms can match in any order, not just as typed (default: _Z_MATCH=unordered)"),
+        )
         .arg(
             Arg::new("current-dir")
                 .short('c')
@@ -217,6 +123,49 @@ fn run() -> Result<Return> {
                 .action(ArgAction::SetTrue)
                 .help("show all matching values"),
         )
+        .arg(
+            Arg::new("print0")
+                .short('0')
+                .long("print0")
+                .action(ArgAction::SetTrue)
+                .help("list just the paths, each followed by a NUL, e.g. for `fzf --read0`; implies --list. With --add-from-stdin, read NUL separated paths"),
+        )
+        .arg(
+            Arg::new("no-scores")
+                .long("no-scores")
+                .action(ArgAction::SetTrue)
+                .help("list just the paths, without the score column"),
+        )
+        .arg(
+            Arg::new("limit")
+                .short('n')
+                .long("limit")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("only show the best N matches (default: everything, or for --complete, _Z_COMPLETE_LIMIT or 50)"),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .action(ArgAction::SetTrue)
+                .help("list matches as a JSON array, best first; implies --list. Non-UTF-8 paths are converted lossily"),
+        )
+        .arg(
+            Arg::new("echo")
+                .short('e')
+                .long("echo")
+                .action(ArgAction::SetTrue)
+                .help("print the best match instead of changing to it"),
+        )
+        .arg(
+            Arg::new("stat")
+                .long("stat")
+                .value_name("PATH")
+                .num_args(0..=1)
+                .default_missing_value("")
+                .value_parser(clap::value_parser!(OsString))
+                .help("explain the scores of an entry (default: the current dir), or with --list, of every match"),
+        )
         .arg(
             Arg::new("expressions")
                 .num_args(0..)
@@ -226,7 +175,57 @@ fn run() -> Result<Return> {
             Arg::new("clean")
                 .long("clean")
                 .action(ArgAction::SetTrue)
-                .help("remove entries which aren't dirs right now"),
+                .help("remove entries which aren't dirs right now, and merge duplicates"),
+        )
+        .arg(
+            Arg::new("fsck")
+                .long("fsck")
+                .action(ArgAction::SetTrue)
+                .help("show every line of the data files which can't be understood"),
+        )
+        .arg(
+            Arg::new("older-than")
+                .long("older-than")
+                .value_name("AGE")
+                .value_parser(parse_age)
+                .requires("clean")
+                .help("with --clean, also remove entries not visited for this long: seconds, or e.g. 12h, 90d, 2w, 6m (months), 1y"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .requires("clean")
+                .help("with --clean, list what would be removed, without changing anything"),
+        )
+        .arg(
+            Arg::new("verbose")
+                .long("verbose")
+                .action(ArgAction::SetTrue)
+                .requires("clean")
+                .help("with --clean, list everything which was removed"),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .action(ArgAction::SetTrue)
+                .requires("clean")
+                .help("with --clean, also remove entries which can't be checked, e.g. on an unmounted share"),
+        )
+        .arg(
+            Arg::new("import")
+                .long("import")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("merge entries from a z, autojump, or `zoxide query --list --score` file"),
+        )
+        .arg(
+            Arg::new("import-format")
+                .long("import-format")
+                .value_name("FORMAT")
+                .value_parser(["z", "autojump", "zoxide"])
+                .requires("import")
+                .help("format of the --import file (default: guess)"),
         )
         .arg(
             Arg::new("add-to-profile")
@@ -240,6 +239,7 @@ fn run() -> Result<Return> {
                 .long("add")
                 .hide_short_help(true)
                 .value_name("PATH")
+                .value_parser(clap::value_parser!(OsString))
                 .help("add a new entry to the database"),
         )
         .arg(
@@ -247,48 +247,230 @@ fn run() -> Result<Return> {
                 .long("add-blocking")
                 .hide_short_help(true)
                 .value_name("PATH")
-                .help("add a new entry, without forking"),
+                .num_args(1..)
+                .value_parser(clap::value_parser!(OsString))
+                .help("add new entries, without going into the background"),
+        )
+        .arg(
+            Arg::new("add-background")
+                .long("add-background")
+                .hide(true)
+                .value_name("PATH")
+                .value_parser(clap::value_parser!(OsString))
+                .help("--add's background child: add quietly, giving up if the data file is busy"),
+        )
+        .arg(
+            Arg::new("add-from-stdin")
+                .long("add-from-stdin")
+                .hide_short_help(true)
+                .action(ArgAction::SetTrue)
+                .help("add every path on stdin, one per line, in one go"),
+        )
+        .arg(
+            Arg::new("weight")
+                .long("weight")
+                .hide_short_help(true)
+                .value_name("N")
+                .value_parser(clap::value_parser!(f32))
+                .allow_negative_numbers(true)
+                .requires("add-mode")
+                .help("count the add as this many visits, e.g. to push a new project up the list (default: 1, minimum: 0.98)"),
+        )
+        .arg(
+            Arg::new("exclude")
+                .long("exclude")
+                .hide_short_help(true)
+                .value_name("DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .action(ArgAction::Append)
+                .help("don't add this dir, or anything below it (in addition to _Z_EXCLUDE_DIRS)"),
+        )
+        .arg(
+            Arg::new("max-score")
+                .long("max-score")
+                .hide(true)
+                .value_name("SCORE")
+                .value_parser(clap::value_parser!(f32))
+                .help("age entries once their ranks total more than this (default: _Z_MAX_SCORE or 9000)"),
+        )
+        .arg(
+            Arg::new("decay")
+                .long("decay")
+                .hide(true)
+                .value_name("FACTOR")
+                .value_parser(clap::value_parser!(f32))
+                .help("multiply every rank by this when aging (default: 0.99)"),
+        )
+        .arg(
+            Arg::new("lock-timeout-ms")
+                .long("lock-timeout-ms")
+                .hide(true)
+                .value_name("MILLIS")
+                .value_parser(clap::value_parser!(u64))
+                .help("give up waiting for the data file after this long (default: _Z_LOCK_TIMEOUT_MS or 2000)"),
         )
         .arg(
             Arg::new("complete")
                 .long("complete")
                 .value_name("PREFIX")
+                .num_args(0..=1)
+                .default_missing_value("")
                 .hide_short_help(true)
                 .help("the line we're trying to complete"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("completions")
+                .long("completions")
+                .value_name("SHELL")
+                .value_parser(clap::value_parser!(Shell))
+                .hide(true)
+                .help("print a completion script for zrs itself"),
+        )
+}
+
+fn run() -> Result<Return> {
+    let matches = build_cli().get_matches();
+
+    if let Some(&shell) = matches.get_one::<Shell>("completions") {
+        clap_complete::generate(shell, &mut build_cli(), "zrs", &mut io::stdout());
+        return Ok(Return::Success);
+    }
+
+    // the prompt hook and completion throw away stderr, so moving the history over waits
+    // for something where the notice will be seen
+    let migrate = matches.get_raw("add").is_none()
+        && matches.get_raw("add-background").is_none()
+        && matches.get_raw("add-blocking").is_none()
+        && matches.get_one::<String>("complete").is_none();
+
+    let owner = z_owner()?;
+    let data_files = data_file_paths(env::var_os("_Z_DATA"), || match &owner {
+        Some(owner) => owner_data_file(owner, migrate),
+        None => resolve_data_file(migrate),
+    })?;
+    // everything which writes only writes here
+    let data_file = data_files[0].clone();
+
+    let write = store::WriteOptions {
+        #[cfg(unix)]
+        owner,
+        lock_timeout: lock_timeout(matches.get_one::<u64>("lock-timeout-ms").copied())?,
+        ..store::WriteOptions::default()
+    };
 
     {
-        if let Some(mut blocking) = matches.get_raw("add-blocking") {
-            return add_entry(&data_file, false, blocking.next().expect("required arg"));
+        let mut excluded = excluded_dirs();
+        if let Some(extra) = matches.get_many::<PathBuf>("exclude") {
+            excluded.extend(extra.cloned());
+        }
+
+        let aging = aging(
+            matches.get_one::<f32>("max-score").copied(),
+            matches.get_one::<f32>("decay").copied(),
+        )?;
+        let weight = weight(matches.get_one::<f32>("weight").copied())?;
+
+        if let Some(mut background) = matches.get_raw("add-background") {
+            let path = background.next().expect("required argument");
+            return add_entry(&data_file, &write, false, path, &excluded, weight, aging);
+        }
+        if let Some(blocking) = matches.get_raw("add-blocking") {
+            let paths = blocking.map(PathBuf::from).collect();
+            return add_entries(&data_file, &write, paths, &excluded, weight, aging);
+        }
+        if matches.get_flag("add-from-stdin") {
+            let mut input = Vec::new();
+            io::stdin()
+                .read_to_end(&mut input)
+                .with_context(|| anyhow!("reading paths from stdin"))?;
+            let terminator = if matches.get_flag("print0") {
+                b'\0'
+            } else {
+                b'\n'
+            };
+            let paths = split_input(&input, terminator);
+            return add_entries(&data_file, &write, paths, &excluded, weight, aging);
         }
         if let Some(mut normal) = matches.get_raw("add") {
-            return add_entry(&data_file, true, normal.next().expect("required argument"));
+            let path = normal.next().expect("required argument");
+            return add_entry(&data_file, &write, true, path, &excluded, weight, aging);
         }
     }
 
-    if let Some(line) = matches.get_one::<&str>("complete") {
-        return complete(&data_file, line);
+    let case = if matches.get_flag("case-sensitive") {
+        CaseMode::Sensitive
+    } else if matches.get_flag("ignore-case") {
+        CaseMode::Insensitive
+    } else {
+        CaseMode::default()
+    };
+
+    let frecency = frecency(matches.get_one::<String>("frecency"))?;
+    let limit = matches
+        .get_one::<u64>("limit")
+        .map(|&limit| usize::try_from(limit).unwrap_or(usize::MAX));
+
+    if let Some(line) = matches.get_one::<String>("complete") {
+        let limit = match limit {
+            Some(limit) => Some(limit),
+            None => complete_limit()?,
+        };
+        return complete(&data_files, line, case, frecency, limit);
     }
 
     if matches.get_flag("clean") {
-        return clean(&data_file);
+        let options = CleanOptions {
+            older_than: matches.get_one::<u64>("older-than").copied(),
+            dry_run: matches.get_flag("dry-run"),
+            verbose: matches.get_flag("verbose"),
+            force: matches.get_flag("force"),
+        };
+        return clean(&data_file, &write, &options);
+    }
+
+    if matches.get_flag("fsck") {
+        return fsck(&data_files);
+    }
+
+    if let Some(from) = matches.get_one::<PathBuf>("import") {
+        let format = match matches.get_one::<String>("import-format") {
+            Some(format) => Some(format.parse()?),
+            None => None,
+        };
+        return import(&data_file, &write, from, format);
     }
 
     if matches.get_flag("add-to-profile") {
         return add_to_profile();
     }
 
+    let stat = matches.get_one::<OsString>("stat");
+    if let Some(path) = stat {
+        if !matches.get_flag("list") {
+            return stat_path(&data_files, path, frecency);
+        }
+        ensure!(
+            path.is_empty(),
+            "--stat --list explains the matches, not a PATH"
+        );
+    }
+
     let mode = if matches.get_flag("recent") {
         Scorer::Recent(unix_time())
     } else if matches.get_flag("rank") {
         Scorer::Rank
     } else {
-        Scorer::Frecent(unix_time())
+        Scorer::Frecent(unix_time(), frecency)
     };
 
-    let mut list = matches.get_flag("list");
+    let json = matches.get_flag("json");
+    let print0 = matches.get_flag("print0");
+    let mut list = matches.get_flag("list") || json || print0;
+    let echo = matches.get_flag("echo");
+    let literal = matches.get_flag("literal") || env_flag("_Z_LITERAL");
+    let any_order = matches.get_flag("any-order") || unordered_by_default()?;
     let mut expr = String::new();
+    let mut unordered = Vec::new();
 
     if matches.get_flag("current-dir") {
         expr.push_str(&regex::escape(
@@ -297,15 +479,23 @@ fn run() -> Result<Return> {
                 .to_str()
                 .ok_or_else(|| anyhow!("current directory isn't valid utf-8"))?,
         ));
-        expr.push('/');
+        expr.push_str(&regex::escape(path::MAIN_SEPARATOR_STR));
     }
 
+    let mut last_term = None;
+
     if let Some(values) = matches.get_many::<String>("expressions") {
         for val in values {
-            if !expr.is_empty() {
-                expr.push_str(".*");
+            if any_order {
+                unordered.push(if literal {
+                    regex::escape(val)
+                } else {
+                    val.to_string()
+                });
+            } else {
+                zrs::push_term(&mut expr, val, literal);
             }
-            expr.push_str(val);
+            last_term = Some(val);
         }
     } else {
         // even if there wasn't an explicit request to list, we had no expressions,
@@ -313,7 +503,41 @@ fn run() -> Result<Return> {
         list = true;
     }
 
-    let table = search(&data_file, expr.as_str(), mode).with_context(|| anyhow!("main search"))?;
+    let last_term = last_term.map(|term| {
+        if literal {
+            regex::escape(term)
+        } else {
+            term.to_string()
+        }
+    });
+
+    let query = Query {
+        expr,
+        unordered,
+        last_term,
+        case,
+        // the dir may have been deleted out from under us; then there's nothing to prefer
+        cwd: env::current_dir().ok(),
+        cwd_boost: cwd_boost()?,
+    };
+
+    if stat.is_some() {
+        return stat_list(&data_files, &query, mode, frecency, limit);
+    }
+
+    let table = match zrs::search_files(&data_files, &query, mode) {
+        Ok(found) => warn_searching(found.warnings, found.value),
+        Err(e @ zrs::Error::Regex { .. }) if !literal => {
+            return Err(e).with_context(|| anyhow!("main search (--literal disables regexes)"))
+        }
+        Err(e) => return Err(e).with_context(|| anyhow!("main search")),
+    };
+
+    if json {
+        let stdout = io::stdout();
+        write_json(&mut stdout.lock(), best(table, limit).iter().rev())?;
+        return Ok(Return::Success);
+    }
 
     if table.is_empty() {
         // It's empty!
@@ -321,136 +545,1063 @@ fn run() -> Result<Return> {
     }
 
     if list {
-        for row in table {
-            println!("{:>10.3} {:?}", row.score, row.path);
-        }
+        let scores = !(echo || print0 || matches.get_flag("no-scores"));
+        let terminator = if print0 { b'\0' } else { b'\n' };
+        let stdout = io::stdout();
+        // not for the DoCd path, which may have to skip past the best few
+        write_list(&mut stdout.lock(), &best(table, limit), scores, terminator)?;
         Ok(Return::Success)
     } else {
-        for row in table.into_iter().rev() {
-            if !row.path.is_dir() {
-                eprintln!("not a dir (run --clean to expunge): {:?}", row.path);
-                continue;
-            }
-            println!("{}", row.path.to_string_lossy());
+        // `z zrs` from inside zrs wants to go somewhere else; `--echo` is just asking
+        let here = match &query.cwd {
+            Some(cwd) if !echo => Some(canonical(cwd)),
+            _ => None,
+        };
+
+        let row = match destination(&table, here.as_deref(), Path::is_dir) {
+            Some(row) => row,
+            None => return Ok(Return::NoOutput),
+        };
 
-            return Ok(Return::DoCd);
+        // the raw bytes, not a lossy string, so the shell can actually get there
+        let mut out = store::path_bytes(&row.path).to_vec();
+        out.push(b'\n');
+        io::stdout()
+            .write_all(&out)
+            .with_context(|| anyhow!("writing destination"))?;
+
+        Ok(if echo { Return::Success } else { Return::DoCd })
+    }
+}
+
+/// The best row which is still a directory, from a worst first table. `here`, where we
+/// already are, is only picked if there's nowhere else to go.
+fn destination<'t>(
+    table: &'t [ScoredRow],
+    here: Option<&Path>,
+    is_dir: impl Fn(&Path) -> bool,
+) -> Option<&'t ScoredRow> {
+    let mut fallback = None;
+    for row in table.iter().rev() {
+        if !is_dir(&row.path) {
+            eprintln!("not a dir (run --clean to expunge): {:?}", row.path);
+            continue;
         }
+        if here.is_some_and(|here| here == row.path || here == canonical(&row.path)) {
+            fallback = fallback.or(Some(row));
+            continue;
+        }
+        return Some(row);
+    }
+    fallback
+}
 
-        Ok(Return::NoOutput)
+/// the raw path bytes, so they survive being piped into `xargs` or `fzf`,
+/// with the score column for humans
+fn write_list<W: Write>(out: W, rows: &[ScoredRow], scores: bool, terminator: u8) -> Result<()> {
+    let mut out = io::BufWriter::new(out);
+    for row in rows {
+        if scores {
+            write!(out, "{:>10.3} ", row.score)?;
+        }
+        out.write_all(store::path_bytes(&row.path))?;
+        out.write_all(&[terminator])?;
     }
+    out.flush()?;
+    Ok(())
 }
 
-fn add_entry(data_file: &PathBuf, non_blocking_add: bool, path: &OsStr) -> Result<Return> {
-    // this must not be called while there are threaded operations running
-    if non_blocking_add && fork_is_parent().with_context(|| anyhow!("forking"))? {
-        return Ok(Return::NoOutput);
+/// keep only the `limit` best rows; the table is worst first, so they're at the end
+fn best(mut table: Vec<ScoredRow>, limit: Option<usize>) -> Vec<ScoredRow> {
+    if let Some(limit) = limit {
+        let excess = table.len().saturating_sub(limit);
+        table.drain(..excess);
     }
+    table
+}
 
-    store::update_file(data_file, |table| do_add(table, path))
-        .with_context(|| anyhow!("adding to file"))?;
+fn write_json<'r, W: Write>(mut out: W, rows: impl Iterator<Item = &'r ScoredRow>) -> Result<()> {
+    out.write_all(b"[")?;
+    let mut empty = true;
+    for (i, row) in rows.enumerate() {
+        empty = false;
+        if 0 != i {
+            out.write_all(b",")?;
+        }
+        write!(
+            out,
+            "\n  {{\"path\": {}, \"rank\": {}, \"time\": {}, \"score\": {}}}",
+            json_string(&row.path.to_string_lossy()),
+            row.rank,
+            row.time,
+            row.score
+        )?;
+    }
+    out.write_all(if empty { b"]\n" } else { b"\n]\n" })?;
+    Ok(())
+}
 
-    Ok(Return::NoOutput)
+fn json_string(val: &str) -> String {
+    let mut ret = String::with_capacity(val.len() + 2);
+    ret.push('"');
+    for c in val.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if c < ' ' => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
 }
 
-fn complete(data_file: &PathBuf, mut line: &str) -> Result<Return> {
-    let cmd = env::var("_Z_CMD").unwrap_or_else(|_err| "z".to_string());
-    if line.starts_with(&cmd) {
-        line = line[cmd.len()..].trim_start();
+/// `--stat PATH`: everything we know about one entry
+fn stat_path(data_files: &[PathBuf], path: &OsStr, frecency: Frecency) -> Result<Return> {
+    let path = if path.is_empty() {
+        env::current_dir().with_context(|| anyhow!("finding current dir"))?
+    } else {
+        PathBuf::from(path)
+    };
+    // look it up the way it would have been added
+    let path = if resolve_symlinks() {
+        canonical(&path)
+    } else {
+        path
+    };
+
+    let table = store::parse_files(data_files)?;
+    let table = warn_searching(table.warnings, table.value);
+
+    match table.iter().find(|row| row.path == path) {
+        Some(row) => {
+            print_stat(row, &table, unix_time(), frecency);
+            Ok(Return::Success)
+        }
+        None => {
+            eprintln!("not in the database: {:?}", path);
+            Ok(Return::NoOutput)
+        }
     }
+}
 
-    let escaped = regex::escape(line);
+/// `--stat --list`: everything we know about every match, in the usual list order
+fn stat_list(
+    data_files: &[PathBuf],
+    query: &Query,
+    mode: Scorer,
+    frecency: Frecency,
+    limit: Option<usize>,
+) -> Result<Return> {
+    let table = store::parse_files(data_files)?;
+    let table = warn_searching(table.warnings, table.value);
+
+    let found = zrs::search_table(&table, query, mode).with_context(|| anyhow!("searching"))?;
+    let found = best(found, limit);
+    if found.is_empty() {
+        return Ok(Return::NoOutput);
+    }
 
-    for row in search(data_file, &escaped, Scorer::Frecent(unix_time()))
-        .with_context(|| anyhow!("searching for completion data"))?
-        .into_iter()
-        .rev()
-    {
-        println!("{}", row.path.to_string_lossy());
+    let now = unix_time();
+    for (i, found) in found.into_iter().enumerate() {
+        if 0 != i {
+            println!();
+        }
+        let row = Row {
+            path: found.path,
+            rank: found.rank,
+            time: found.time,
+        };
+        print_stat(&row, &table, now, frecency);
     }
 
     Ok(Return::Success)
 }
 
-fn clean(data_file: &PathBuf) -> Result<Return> {
-    let modified = store::update_file(data_file, |table| {
-        let start = table.len();
-        table.retain(|row| row.path.is_dir());
-        Ok(start - table.len())
-    })
-    .with_context(|| anyhow!("cleaning data file"))?;
-
+fn print_stat(row: &Row, table: &[Row], now: u64, frecency: Frecency) {
+    let age = zrs::time_delta(now, row.time);
+    println!("{:?}", row.path);
     println!(
-        "Cleaned {} {}.",
-        modified,
-        if 1 == modified { "entry" } else { "entries" }
+        "  rank:          {:.3} ({} percentile)",
+        row.rank,
+        ordinal(percentile(row.rank, table).round() as u32)
     );
+    println!("  last visited:  {} ({})", row.time, format_age(age));
+    println!("  rank score:    {:.3}", row.rank);
+    // written this way to avoid printing `-0.000`
+    println!("  recent score:  {:.3}", 0. - age as f32);
+    println!("  frecent score: {:.3}", frecency.score(row.rank, age));
+}
 
-    Ok(Return::Success)
+/// the percentage of the table with a rank no higher than this one
+fn percentile(rank: f32, table: &[Row]) -> f32 {
+    if table.is_empty() {
+        return 100.;
+    }
+    let below = table.iter().filter(|row| row.rank <= rank).count();
+    100. * below as f32 / table.len() as f32
 }
 
-fn add_to_profile() -> Result<Return> {
-    let mut data =
-        dirs::data_local_dir().ok_or_else(|| anyhow!("couldn't find your .local/share dir"))?;
+/// `1st`, `2nd`, `11th`, ...
+fn ordinal(n: u32) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
 
-    data.push("zrs");
-    fs::create_dir_all(&data).with_context(|| anyhow!("creating {:?}", data))?;
+/// `3 days ago`, roughly; only the largest unit is shown
+fn format_age(secs: u64) -> String {
+    const MINUTE: u64 = 60;
+    const HOUR: u64 = MINUTE * 60;
+    const DAY: u64 = HOUR * 24;
+    const WEEK: u64 = DAY * 7;
+    const YEAR: u64 = DAY * 365;
+
+    let (count, unit) = match secs {
+        0 => return "just now".to_string(),
+        s if s < MINUTE => (s, "second"),
+        s if s < HOUR => (s / MINUTE, "minute"),
+        s if s < DAY => (s / HOUR, "hour"),
+        s if s < 2 * WEEK => (s / DAY, "day"),
+        s if s < YEAR => (s / WEEK, "week"),
+        s => (s / YEAR, "year"),
+    };
 
-    data.push("z.sh");
-    fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&data)
-        .with_context(|| anyhow!("opening {:?}", data))?
-        .write_all(HELPER_SCRIPT)
-        .with_context(|| anyhow!("writing helper script"))?;
+    format!(
+        "{} {}{} ago",
+        count,
+        unit,
+        if 1 == count { "" } else { "s" }
+    )
+}
 
-    println!("written helper script to {:?}", data);
+fn add_entry(
+    data_file: &PathBuf,
+    write: &store::WriteOptions,
+    non_blocking_add: bool,
+    path: &OsStr,
+    excluded: &[PathBuf],
+    weight: f32,
+    aging: Aging,
+) -> Result<Return> {
+    // checked before spawning, so we don't spawn a child just to do nothing
+    if !zrs::worth_adding(Path::new(path)) || is_excluded(Path::new(path), excluded) {
+        return Ok(Return::NoOutput);
+    }
 
-    let data = data
-        .to_str()
-        .ok_or_else(|| anyhow!("lazily refusing to handle non-utf8 paths"))?;
-    ensure!(
-        !data.contains('\''),
-        "cowardly refusing to handle paths with single quotes"
-    );
+    if non_blocking_add {
+        spawn_add(path, write, weight, aging)
+            .with_context(|| anyhow!("starting background add"))?;
+        return Ok(Return::NoOutput);
+    }
 
-    let source_line = format!("\n\n. '{}'\n", data);
+    let path = if resolve_symlinks() {
+        canonical(Path::new(path))
+    } else {
+        PathBuf::from(path)
+    };
 
-    let path = home_dir()?;
+    // this is in the background, where nobody would hear about a backup; leave
+    // the unparseable lines for `--fsck` to find, and `--clean` to deal with
+    let write = store::WriteOptions {
+        keep_unparseable: true,
+        ..write.clone()
+    };
 
-    for rc in &[".zshrc", ".bashrc"] {
-        let mut path = path.to_path_buf();
-        path.push(rc);
-        match fs::read(&path) {
-            Ok(current) => {
-                if twoway::find_bytes(&current, data.as_bytes()).is_some() {
-                    println!("appears to already be present, not appending: {:?}", path);
-                    continue;
-                }
-            }
-            Err(e) => {
-                eprintln!("couldn't open {:?}: {:?}", path, e);
+    let added = match store::update_file(data_file, &write, |table| {
+        zrs::do_add_weighted(table, &path, weight, aging);
+        Ok(())
+    }) {
+        // adds happen on every prompt; if someone else is hogging the file, just skip this one
+        Err(zrs::Error::Locked { .. }) => return Ok(Return::NoOutput),
+        other => other.with_context(|| anyhow!("adding to file"))?,
+    };
+    warn_searching(added.warnings, ());
+
+    Ok(Return::NoOutput)
+}
+
+/// every path in a single rewrite of the data file, instead of one rewrite each
+fn add_entries(
+    data_file: &PathBuf,
+    write: &store::WriteOptions,
+    paths: Vec<PathBuf>,
+    excluded: &[PathBuf],
+    weight: f32,
+    aging: Aging,
+) -> Result<Return> {
+    let resolve = resolve_symlinks();
+    let mut skipped = 0;
+    let mut wanted = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        // before canonicalizing, which would make relative paths absolute
+        if !zrs::worth_adding(&path) {
+            eprintln!("skipping (relative, home or root): {:?}", path);
+            skipped += 1;
+            continue;
+        }
+        if is_excluded(&path, excluded) {
+            skipped += 1;
+            continue;
+        }
+        wanted.push(if resolve { canonical(&path) } else { path });
+    }
+
+    let added = store::update_file(data_file, write, |table| {
+        let mut recorded = Vec::with_capacity(wanted.len());
+        for path in &wanted {
+            if zrs::do_add_weighted(table, path, weight, aging) {
+                recorded.push(path.as_path());
+            } else {
+                eprintln!("skipping (relative, home or root): {:?}", path);
+            }
+        }
+
+        // only count what'll actually be in the file; aging, or the format, can take rows away
+        let written: HashSet<&Path> = table
+            .iter()
+            .filter(|row| store::is_written(row))
+            .map(|row| row.path.as_path())
+            .collect();
+        Ok::<_, zrs::Error>(
+            recorded
+                .into_iter()
+                .filter(|path| written.contains(path))
+                .count(),
+        )
+    })
+    .with_context(|| anyhow!("adding to file"))?;
+    let added = warn(added.warnings, added.value);
+    skipped += wanted.len() - added;
+
+    println!(
+        "Added {} {}, skipped {}.",
+        added,
+        plural(added, "entry", "entries"),
+        skipped
+    );
+
+    Ok(Return::Success)
+}
+
+/// `find`'s output, or `find -print0`'s
+fn split_input(input: &[u8], terminator: u8) -> Vec<PathBuf> {
+    input
+        .split(|&b| terminator == b)
+        .filter(|path| !path.is_empty())
+        .map(store::path_from_bytes)
+        .collect()
+}
+
+/// like z.sh, resolve symlinks unless `_Z_NO_RESOLVE_SYMLINKS` is set
+fn resolve_symlinks() -> bool {
+    !env_flag("_Z_NO_RESOLVE_SYMLINKS")
+}
+
+/// like `[ "$VAR" ]`: set, and not empty
+fn env_flag(name: &str) -> bool {
+    env::var_os(name).is_some_and(|val| !val.is_empty())
+}
+
+/// the dir may have gone away by the time we get here; then just use what we were given
+fn canonical(path: &Path) -> PathBuf {
+    match fs::canonicalize(path) {
+        Ok(path) => without_verbatim_prefix(path),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// `fs::canonicalize` gives windows' `\\?\C:\...` form, which the current dir, and people,
+/// don't use. Drop the prefix, unless the path is too long to work without it.
+#[cfg(windows)]
+fn without_verbatim_prefix(path: PathBuf) -> PathBuf {
+    use std::path::Component;
+    use std::path::Prefix;
+
+    const MAX_PATH: usize = 260;
+
+    let drive = match path.components().next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::VerbatimDisk(drive) => Some(drive),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    match drive {
+        Some(drive) if path.as_os_str().len() < MAX_PATH => {
+            let mut plain = PathBuf::from(format!("{}:", drive as char));
+            plain.extend(path.components().skip(1));
+            plain
+        }
+        _ => path,
+    }
+}
+
+#[cfg(not(windows))]
+fn without_verbatim_prefix(path: PathBuf) -> PathBuf {
+    path
+}
+
+/// flags win over `_Z_MAX_SCORE`, which wins over the defaults
+fn aging(max_score: Option<f32>, decay: Option<f32>) -> Result<Aging> {
+    let default = Aging::default();
+
+    let max_score = match (max_score, env::var("_Z_MAX_SCORE")) {
+        (Some(flag), _) => flag,
+        (None, Ok(env)) if !env.is_empty() => env
+            .parse()
+            .with_context(|| anyhow!("parsing _Z_MAX_SCORE: {:?}", env))?,
+        (None, _) => default.max_score(),
+    };
+
+    Ok(Aging::new(max_score, decay.unwrap_or(default.decay()))?)
+}
+
+/// how many visits an add counts as; 1, unless `--weight` says otherwise
+fn weight(flag: Option<f32>) -> Result<f32> {
+    let weight = flag.unwrap_or(1.);
+    // anything less, and a new entry would be thrown away as soon as it was written
+    ensure!(
+        weight.is_finite() && weight >= store::MIN_RANK,
+        "--weight must be at least {}, not {}",
+        store::MIN_RANK,
+        weight
+    );
+    Ok(weight)
+}
+
+/// the flag wins over `_Z_LOCK_TIMEOUT_MS`, which wins over the default
+fn lock_timeout(flag: Option<u64>) -> Result<Duration> {
+    Ok(match (flag, env::var("_Z_LOCK_TIMEOUT_MS")) {
+        (Some(flag), _) => Duration::from_millis(flag),
+        (None, Ok(env)) if !env.is_empty() => Duration::from_millis(
+            env.parse()
+                .with_context(|| anyhow!("parsing _Z_LOCK_TIMEOUT_MS: {:?}", env))?,
+        ),
+        (None, _) => store::DEFAULT_LOCK_TIMEOUT,
+    })
+}
+
+/// the flag wins over `_Z_FRECENCY`, which wins over the classic steps
+fn frecency(flag: Option<&String>) -> Result<Frecency> {
+    Ok(match (flag, env::var("_Z_FRECENCY")) {
+        (Some(flag), _) => flag.parse()?,
+        (None, Ok(env)) if !env.is_empty() => env
+            .parse()
+            .with_context(|| anyhow!("parsing _Z_FRECENCY"))?,
+        (None, _) => Frecency::Classic,
+    })
+}
+
+/// `_Z_MATCH`: `ordered`, the default, or `unordered`, like `--any-order`
+fn unordered_by_default() -> Result<bool> {
+    Ok(match env::var("_Z_MATCH") {
+        Ok(env) => match env.as_str() {
+            "" | "ordered" => false,
+            "unordered" => true,
+            other => {
+                return Err(anyhow!(
+                    "_Z_MATCH must be \"ordered\" or \"unordered\", not {:?}",
+                    other
+                ))
+            }
+        },
+        Err(_) => false,
+    })
+}
+
+/// `_Z_COMPLETE_LIMIT`, or 50; 0 means no limit
+fn complete_limit() -> Result<Option<usize>> {
+    let limit = match env::var("_Z_COMPLETE_LIMIT") {
+        Ok(env) if !env.is_empty() => env
+            .parse()
+            .with_context(|| anyhow!("parsing _Z_COMPLETE_LIMIT: {:?}", env))?,
+        _ => 50,
+    };
+    Ok(if 0 == limit { None } else { Some(limit) })
+}
+
+/// `_Z_CWD_BOOST`, or the default; 1 turns it off
+fn cwd_boost() -> Result<f32> {
+    let boost = match env::var("_Z_CWD_BOOST") {
+        Ok(env) if !env.is_empty() => env
+            .parse()
+            .with_context(|| anyhow!("parsing _Z_CWD_BOOST: {:?}", env))?,
+        _ => zrs::DEFAULT_CWD_BOOST,
+    };
+    ensure!(
+        boost.is_finite() && boost > 0.,
+        "_Z_CWD_BOOST must be positive, not {}",
+        boost
+    );
+    Ok(boost)
+}
+
+/// directories listed in `_Z_EXCLUDE_DIRS`, colon separated
+fn excluded_dirs() -> Vec<PathBuf> {
+    match env::var_os("_Z_EXCLUDE_DIRS") {
+        Some(dirs) => env::split_paths(&dirs)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+fn is_excluded(path: &Path, excluded: &[PathBuf]) -> bool {
+    // `starts_with` works on whole components, so /tmp doesn't exclude /tmpfs
+    excluded.iter().any(|dir| path.starts_with(dir))
+}
+
+fn complete(
+    data_files: &[PathBuf],
+    line: &str,
+    case: CaseMode,
+    frecency: Frecency,
+    limit: Option<usize>,
+) -> Result<Return> {
+    let cmd = env::var("_Z_CMD").unwrap_or_else(|_err| "z".to_string());
+    let terms = completion_terms(line, &cmd, dirs::home_dir().as_deref());
+
+    // the user is typing a path, not a regex
+    let mut expr = String::new();
+    for term in &terms {
+        zrs::push_term(&mut expr, term, true);
+    }
+
+    let query = Query {
+        last_term: terms.last().map(|term| regex::escape(term)),
+        case,
+        ..Query::new(expr)
+    };
+
+    let found = zrs::search_files(data_files, &query, Scorer::Frecent(unix_time(), frecency))
+        .with_context(|| anyhow!("searching for completion data"))?;
+
+    let found = warn_searching(found.warnings, found.value);
+    for row in best(without_children(found), limit).into_iter().rev() {
+        println!("{}", row.path.to_string_lossy());
+    }
+
+    Ok(Return::Success)
+}
+
+/// The words of a partially typed command line worth searching for: without the command
+/// itself, with a leading `~` expanded, and without trailing slashes, which stored paths
+/// never have.
+fn completion_terms(line: &str, cmd: &str, home: Option<&Path>) -> Vec<String> {
+    let mut words = line.split_whitespace().peekable();
+    let is_cmd = |word: &str| word == cmd || Path::new(word).file_stem() == Some(OsStr::new("zrs"));
+    words.next_if(|word| is_cmd(word));
+
+    words
+        .map(|word| match (word.strip_prefix('~'), home) {
+            (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with(path::is_separator) => {
+                format!("{}{}", home.to_string_lossy(), rest)
+            }
+            _ => word.to_string(),
+        })
+        .map(|word| word.trim_end_matches(path::is_separator).to_string())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Drop repeated rows, and rows below another row; completing to the parent gets there.
+/// Like the search results, the rows are worst first.
+fn without_children(table: Vec<ScoredRow>) -> Vec<ScoredRow> {
+    let paths: HashSet<PathBuf> = table.iter().map(|row| row.path.clone()).collect();
+    let mut seen = HashSet::with_capacity(table.len());
+
+    let mut kept: Vec<ScoredRow> = table
+        .into_iter()
+        .rev()
+        .filter(|row| !row.path.ancestors().skip(1).any(|dir| paths.contains(dir)))
+        .filter(|row| seen.insert(row.path.clone()))
+        .collect();
+    kept.reverse();
+    kept
+}
+
+struct CleanOptions {
+    /// also remove rows not visited for this many seconds
+    older_than: Option<u64>,
+    /// don't write anything, just say what would happen
+    dry_run: bool,
+    /// say what happened to each row
+    verbose: bool,
+    /// remove rows even if we couldn't tell whether they're really gone
+    force: bool,
+}
+
+fn clean(
+    data_file: &PathBuf,
+    write: &store::WriteOptions,
+    options: &CleanOptions,
+) -> Result<Return> {
+    let resolve = resolve_symlinks();
+    let now = unix_time();
+    let cleaning = |table: &mut Vec<Row>| {
+        clean_table(
+            table,
+            now,
+            options.older_than,
+            options.force,
+            resolve,
+            presence,
+        )
+    };
+
+    let cleaned = if options.dry_run {
+        // not even through `update_file`, which would rewrite the file regardless
+        let mut table = match fs::File::open(data_file) {
+            Ok(file) => store::parse(file).with_context(|| anyhow!("reading data file"))?,
+            Err(e) if io::ErrorKind::NotFound == e.kind() => zrs::Outcome {
+                value: Vec::new(),
+                warnings: Vec::new(),
+            },
+            Err(e) => return Err(e).with_context(|| anyhow!("opening {:?}", data_file)),
+        };
+        let cleaned = cleaning(&mut table.value);
+        warn_searching(table.warnings, cleaned)
+    } else {
+        let cleaned = store::update_file(data_file, write, |table| {
+            Ok::<_, zrs::Error>(cleaning(table))
+        })
+        .with_context(|| anyhow!("cleaning data file"))?;
+        warn(cleaned.warnings, cleaned.value)
+    };
+
+    let verb = if options.dry_run {
+        "would remove"
+    } else {
+        "removed"
+    };
+    if options.dry_run || options.verbose {
+        for path in &cleaned.dead {
+            println!("{} (not a dir): {}", verb, path.display());
+        }
+        for path in &cleaned.stale {
+            println!("{} (stale): {}", verb, path.display());
+        }
+    }
+    // always, as this is exactly the case where someone might want to step in
+    for (path, reason) in &cleaned.unsure {
+        eprintln!("kept {:?}, as {} (--force to remove)", path, reason);
+    }
+
+    let stale = match options.older_than {
+        Some(_) => format!(
+            " {} stale {},",
+            cleaned.stale.len(),
+            plural(cleaned.stale.len(), "entry", "entries")
+        ),
+        None => String::new(),
+    };
+    let unsure = match cleaned.unsure.len() {
+        0 => String::new(),
+        unsure => format!(
+            " Kept {} unreachable {}.",
+            unsure,
+            plural(unsure, "entry", "entries")
+        ),
+    };
+
+    println!(
+        "{} {} dead {},{} {} {} {}.{}",
+        if options.dry_run {
+            "Would remove"
+        } else {
+            "Removed"
+        },
+        cleaned.dead.len(),
+        plural(cleaned.dead.len(), "entry", "entries"),
+        stale,
+        if options.dry_run { "merge" } else { "merged" },
+        cleaned.merged,
+        plural(cleaned.merged, "duplicate", "duplicates"),
+        unsure
+    );
+
+    Ok(Return::Success)
+}
+
+/// Whether a row's directory is still there, as far as we can tell.
+#[derive(Clone, Debug, PartialEq)]
+enum Presence {
+    Dir,
+    Gone,
+    /// it can't be seen right now, but that might not last; e.g. a share which isn't mounted
+    Unsure(String),
+}
+
+fn presence(path: &Path) -> Presence {
+    presence_in(path, is_mount_point)
+}
+
+/// [`presence`], with a stand-in for looking at the real mounts
+fn presence_in(path: &Path, is_mount_point: impl Fn(&Path) -> bool) -> Presence {
+    let error = match fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => return Presence::Dir,
+        // something else has taken its place
+        Ok(_) => return Presence::Gone,
+        Err(e) => e,
+    };
+
+    match error.kind() {
+        io::ErrorKind::NotFound => (),
+        io::ErrorKind::NotADirectory => return Presence::Gone,
+        // permissions, a hung network mount, ...
+        _ => return Presence::Unsure(format!("it couldn't be checked: {}", error)),
+    }
+
+    // an unmounted share leaves behind an empty mount point, so if that's where the path
+    // runs out, it's probably just not there right now. Anywhere else, it was deleted.
+    // a file on the way gives NotADirectory, but not everywhere; windows says NotFound
+    let parent = match path
+        .ancestors()
+        .skip(1)
+        .find(|dir| fs::metadata(dir).is_ok_and(|meta| meta.is_dir()))
+    {
+        Some(parent) => parent,
+        None => return Presence::Gone,
+    };
+    match fs::read_dir(parent).map(|mut entries| entries.next().is_none()) {
+        Ok(true) if is_mount_point(parent) => Presence::Unsure(format!(
+            "{:?} is an empty mount point, maybe something isn't mounted",
+            parent
+        )),
+        Ok(_) => Presence::Gone,
+        Err(e) => Presence::Unsure(format!("{:?} couldn't be read: {}", parent, e)),
+    }
+}
+
+/// Whether something gets mounted on `dir`: it's on a different filesystem to its parent,
+/// or it's in the mount tables, which also covers it not being mounted right now.
+#[cfg(unix)]
+fn is_mount_point(dir: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let device = |path: &Path| fs::metadata(path).map(|meta| meta.dev()).ok();
+    if let (Some(dir), Some(parent)) = (device(dir), dir.parent().and_then(device)) {
+        if dir != parent {
+            return true;
+        }
+    }
+
+    ["/etc/fstab", "/proc/mounts"]
+        .iter()
+        .any(|table| fs::read_to_string(table).is_ok_and(|table| mount_table_lists(&table, dir)))
+}
+
+#[cfg(not(unix))]
+fn is_mount_point(_dir: &Path) -> bool {
+    false
+}
+
+/// `fstab(5)` format: the mount point is the second field, with spaces written as `\040`
+#[cfg(unix)]
+fn mount_table_lists(table: &str, dir: &Path) -> bool {
+    table
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .any(|point| Path::new(&point.replace("\\040", " ")) == dir)
+}
+
+/// What `--clean` took out of the table, or would have.
+#[derive(Debug, Default)]
+struct Cleaned {
+    dead: Vec<PathBuf>,
+    stale: Vec<PathBuf>,
+    /// left in, with why
+    unsure: Vec<(PathBuf, String)>,
+    merged: usize,
+}
+
+fn clean_table(
+    table: &mut Vec<Row>,
+    now: u64,
+    older_than: Option<u64>,
+    force: bool,
+    resolve: bool,
+    presence: impl Fn(&Path) -> Presence,
+) -> Cleaned {
+    let mut cleaned = Cleaned::default();
+
+    let mut kept = Vec::with_capacity(table.len());
+    for row in table.drain(..) {
+        match presence(&row.path) {
+            Presence::Dir => (),
+            Presence::Unsure(reason) if !force => cleaned.unsure.push((row.path.clone(), reason)),
+            Presence::Gone | Presence::Unsure(_) => {
+                cleaned.dead.push(row.path);
                 continue;
             }
         }
-        match fs::OpenOptions::new().append(true).open(&path) {
-            Ok(mut zshrc) => {
-                zshrc.write_all(source_line.as_bytes())?;
-                drop(zshrc);
-                println!("appended '. .../z.sh' to {:?}", path);
+
+        // rows from the future (clock skew) have an age of zero, so are kept
+        if older_than.is_some_and(|older_than| zrs::time_delta(now, row.time) > older_than) {
+            cleaned.stale.push(row.path);
+            continue;
+        }
+
+        kept.push(row);
+    }
+    *table = kept;
+
+    cleaned.merged = zrs::merge_duplicates(table, |path| {
+        let path = zrs::trim_trailing_slashes(path);
+        if resolve {
+            canonical(&path)
+        } else {
+            path
+        }
+    });
+
+    cleaned
+}
+
+fn fsck(data_files: &[PathBuf]) -> Result<Return> {
+    let mut bad = 0;
+    for data_file in data_files {
+        let file = match fs::File::open(data_file) {
+            Ok(file) => file,
+            Err(e) if io::ErrorKind::NotFound == e.kind() => {
+                eprintln!("no data file at {:?}", data_file);
+                continue;
+            }
+            Err(e) => return Err(e).with_context(|| anyhow!("opening {:?}", data_file)),
+        };
+
+        let table = store::parse(file).with_context(|| anyhow!("reading {:?}", data_file))?;
+        for warning in table.warnings {
+            if let Warning::BadLine {
+                number,
+                line,
+                error,
+            } = warning
+            {
+                println!("{}:{}: {}: {:?}", data_file.display(), number, error, line);
+                bad += 1;
             }
-            Err(e) => eprintln!("couldn't append to {:?}: {:?}", path, e),
         }
     }
 
+    ensure!(
+        0 == bad,
+        "found {} unparseable {}; --clean drops them, after backing up the file",
+        bad,
+        plural(bad, "line", "lines")
+    );
     Ok(Return::Success)
 }
 
-fn compare_score(left: &ScoredRow, right: &ScoredRow) -> cmp::Ordering {
-    left.score
-        .partial_cmp(&right.score)
-        .expect("no NaNs in scoring")
+fn plural<'s>(count: usize, one: &'s str, many: &'s str) -> &'s str {
+    if 1 == count {
+        one
+    } else {
+        many
+    }
+}
+
+/// `90d` to seconds; a bare number is already seconds. `m` is months, not minutes,
+/// as nobody is going to want to clean by the minute.
+fn parse_age(val: &str) -> Result<u64, String> {
+    const DAY: u64 = 24 * 60 * 60;
+
+    let split = val.find(|c: char| !c.is_ascii_digit()).unwrap_or(val.len());
+    let (count, unit) = val.split_at(split);
+
+    let count: u64 = count
+        .parse()
+        .map_err(|_| format!("expected a number, then an optional unit, not {:?}", val))?;
+
+    let unit = match unit {
+        "" | "s" => 1,
+        "h" => 60 * 60,
+        "d" => DAY,
+        "w" => 7 * DAY,
+        "m" => 30 * DAY,
+        "y" => 365 * DAY,
+        other => {
+            return Err(format!(
+                "unrecognised unit {:?}, try one of: s, h, d, w, m, y",
+                other
+            ))
+        }
+    };
+
+    count
+        .checked_mul(unit)
+        .ok_or_else(|| format!("far too long: {:?}", val))
+}
+
+fn import(
+    data_file: &PathBuf,
+    write: &store::WriteOptions,
+    from: &Path,
+    format: Option<import::Format>,
+) -> Result<Return> {
+    let data = fs::read(from).with_context(|| anyhow!("reading {:?}", from))?;
+    let format = match format {
+        Some(format) => format,
+        None => import::Format::sniff(&data)
+            .ok_or_else(|| anyhow!("couldn't guess the format, try --import-format"))?,
+    };
+
+    let imported = import::parse(&data, format, unix_time());
+    let imported = warn(imported.warnings, imported.value);
+    let total = imported.len();
+
+    let merged = store::update_file(data_file, write, |table| {
+        Ok::<_, zrs::Error>(import::merge(table, imported))
+    })
+    .with_context(|| anyhow!("importing into data file"))?;
+    let merged = warn(merged.warnings, merged.value);
+
+    println!(
+        "imported {} {} ({} merged)",
+        total,
+        if 1 == total { "entry" } else { "entries" },
+        merged
+    );
+
+    Ok(Return::Success)
+}
+
+fn add_to_profile() -> Result<Return> {
+    let mut data =
+        dirs::data_local_dir().ok_or_else(|| anyhow!("couldn't find your .local/share dir"))?;
+
+    data.push("zrs");
+    fs::create_dir_all(&data).with_context(|| anyhow!("creating {:?}", data))?;
+
+    if cfg!(windows) {
+        let script = write_helper(&data, "z.ps1", POWERSHELL_HELPER_SCRIPT)?;
+        let source_line = format!("\r\n\r\n. '{}'\r\n", script);
+        for profile in powershell_profiles()? {
+            if let Some(dir) = profile.parent() {
+                fs::create_dir_all(dir).with_context(|| anyhow!("creating {:?}", dir))?;
+            }
+            append_source_line(&profile, &script, &source_line, true);
+        }
+        return Ok(Return::Success);
+    }
+
+    let script = write_helper(&data, "z.sh", HELPER_SCRIPT)?;
+    let source_line = format!("\n\n. '{}'\n", script);
+
+    let path = home_dir()?;
+
+    for rc in &[".zshrc", ".bashrc"] {
+        append_source_line(&path.join(rc), &script, &source_line, false);
+    }
+
+    if let Some(fish) = fish_config_dir()? {
+        let script = write_helper(&data, "z.fish", FISH_HELPER_SCRIPT)?;
+        let source_line = format!("\n\nsource '{}'\n", script);
+
+        fs::create_dir_all(&fish).with_context(|| anyhow!("creating {:?}", fish))?;
+        append_source_line(&fish.join("config.fish"), &script, &source_line, true);
+    }
+
+    Ok(Return::Success)
+}
+
+fn write_helper(dir: &Path, name: &str, contents: &[u8]) -> Result<String> {
+    let path = dir.join(name);
+    fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&path)
+        .with_context(|| anyhow!("opening {:?}", path))?
+        .write_all(contents)
+        .with_context(|| anyhow!("writing helper script"))?;
+
+    println!("written helper script to {:?}", path);
+
+    let path = path
+        .into_os_string()
+        .into_string()
+        .map_err(|_| anyhow!("lazily refusing to handle non-utf8 paths"))?;
+    ensure!(
+        !path.contains('\''),
+        "cowardly refusing to handle paths with single quotes"
+    );
+
+    Ok(path)
+}
+
+fn append_source_line(rc: &Path, script: &str, source_line: &str, create: bool) {
+    match fs::read(rc) {
+        Ok(current) => {
+            if twoway::find_bytes(&current, script.as_bytes()).is_some() {
+                println!("appears to already be present, not appending: {:?}", rc);
+                return;
+            }
+        }
+        Err(ref e) if create && e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => {
+            eprintln!("couldn't open {:?}: {:?}", rc, e);
+            return;
+        }
+    }
+    match fs::OpenOptions::new().append(true).create(create).open(rc) {
+        Ok(mut file) => match file.write_all(source_line.as_bytes()) {
+            Ok(()) => println!("appended source line to {:?}", rc),
+            Err(e) => eprintln!("couldn't append to {:?}: {:?}", rc, e),
+        },
+        Err(e) => eprintln!("couldn't append to {:?}: {:?}", rc, e),
+    }
+}
+
+/// `$PROFILE` for PowerShell 7 and for Windows PowerShell, if they look like they're in use,
+/// or just for PowerShell 7 if neither does
+fn powershell_profiles() -> Result<Vec<PathBuf>> {
+    let documents =
+        dirs::document_dir().ok_or_else(|| anyhow!("couldn't find your Documents dir"))?;
+    let profile = |dir: &str| documents.join(dir).join("Microsoft.PowerShell_profile.ps1");
+
+    let existing: Vec<PathBuf> = ["PowerShell", "WindowsPowerShell"]
+        .iter()
+        .filter(|dir| documents.join(dir).is_dir())
+        .map(|dir| profile(dir))
+        .collect();
+
+    Ok(if existing.is_empty() {
+        vec![profile("PowerShell")]
+    } else {
+        existing
+    })
+}
+
+/// fish's config dir, if it looks like fish is in use
+fn fish_config_dir() -> Result<Option<PathBuf>> {
+    let config = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir()?.join(".config"),
+    };
+    let fish = config.join("fish");
+
+    let login_shell_is_fish = env::var_os("SHELL")
+        .map(|shell| Path::new(&shell).file_name() == Some(OsStr::new("fish")))
+        .unwrap_or(false);
+
+    Ok(if fish.is_dir() || login_shell_is_fish {
+        Some(fish)
+    } else {
+        None
+    })
 }
 
 enum Return {
@@ -470,30 +1621,208 @@ fn main() -> Result<()> {
     }
 }
 
-fn fork_is_parent() -> Result<bool> {
-    // this is a cut-down version of unistd::daemon(),
-    // except we return instead of exiting. Just being paranoid,
-    // not actually expecting to be running long enough that this will matter.
-    // Unsafe iff the parent's threads are doing other stuff. We don't have threads.
-    match unsafe { unistd::fork()? } {
-        unistd::ForkResult::Parent { .. } => Ok(true),
-        unistd::ForkResult::Child => {
-            env::set_current_dir("/")?;
-            unistd::close(0)?;
-            Ok(false)
+/// Run `--add-blocking` in the background, detached from the shell: in its own process
+/// group, so it doesn't get the shell's signals, and without our stdio, so it can't write
+/// into the middle of whatever the user is doing next. We don't wait for it.
+fn spawn_add(path: &OsStr, write: &store::WriteOptions, weight: f32, aging: Aging) -> Result<()> {
+    let exe = env::current_exe().with_context(|| anyhow!("finding ourselves"))?;
+    let mut command = process::Command::new(exe);
+    command
+        .arg("--max-score")
+        .arg(aging.max_score().to_string())
+        .arg("--decay")
+        .arg(aging.decay().to_string())
+        .arg("--lock-timeout-ms")
+        .arg(write.lock_timeout.as_millis().to_string())
+        .arg("--weight")
+        .arg(weight.to_string())
+        .arg("--add-background")
+        .arg(path)
+        .current_dir("/")
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null());
+
+    #[cfg(unix)]
+    command.process_group(0);
+
+    #[cfg(windows)]
+    {
+        const DETACHED_PROCESS: u32 = 0x0000_0008;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+    }
+
+    command.spawn()?;
+    Ok(())
+}
+
+/// the user named by `_Z_OWNER`, if any
+#[cfg(unix)]
+fn z_owner() -> Result<Option<User>> {
+    let name = match env::var("_Z_OWNER") {
+        Ok(name) if !name.is_empty() => name,
+        _ => return Ok(None),
+    };
+
+    let user = User::from_name(&name)
+        .with_context(|| anyhow!("looking up _Z_OWNER: {:?}", name))?
+        .ok_or_else(|| anyhow!("_Z_OWNER user doesn't exist: {:?}", name))?;
+
+    Ok(Some(user))
+}
+
+/// `_Z_OWNER` is only about unix file ownership, so there can't be one elsewhere
+#[cfg(not(unix))]
+enum User {}
+
+#[cfg(not(unix))]
+fn z_owner() -> Result<Option<User>> {
+    ensure!(
+        env::var_os("_Z_OWNER").is_none_or(|name| name.is_empty()),
+        "_Z_OWNER is only supported on unix"
+    );
+    Ok(None)
+}
+
+/// `_Z_DATA` wins, then the `default`: see [`resolve_data_file`]. `_Z_DATA` can list several
+/// files, colon separated, which are all searched; the first is the only one written to.
+fn data_file_paths<F>(z_data: Option<OsString>, default: F) -> Result<Vec<PathBuf>>
+where
+    F: FnOnce() -> Result<PathBuf>,
+{
+    if let Some(z_data) = z_data {
+        let paths: Vec<PathBuf> = env::split_paths(&z_data)
+            .filter(|path| !path.as_os_str().is_empty())
+            .collect();
+        if !paths.is_empty() {
+            return Ok(paths);
+        }
+    }
+
+    Ok(vec![default()?])
+}
+
+/// `zrs/data` in the local data dir (e.g. `~/.local/share`, or `%LOCALAPPDATA%`), next to
+/// the helper scripts, instead of z.sh's `~/.z`, which z.sh itself might still be using.
+/// Until we `migrate`, an existing `~/.z` is used; see [`data_file_in`].
+fn resolve_data_file(migrate: bool) -> Result<PathBuf> {
+    let dir = dirs::data_local_dir()
+        .ok_or_else(|| anyhow!("couldn't find your .local/share dir"))?
+        .join("zrs");
+    data_file_in(&dir, &home_dir()?.join(".z"), migrate, |_| ())
+}
+
+/// like [`resolve_data_file`], but for the `_Z_OWNER`, while we're probably root
+#[cfg(unix)]
+fn owner_data_file(owner: &User, migrate: bool) -> Result<PathBuf> {
+    let dir = data_dir_for(
+        &owner.dir,
+        dirs::data_local_dir().as_deref(),
+        dirs::home_dir().as_deref(),
+    );
+
+    // don't leave them with dirs they can't write to, or a copy they can't read; the file
+    // itself is fixed up every time it's written anyway
+    data_file_in(&dir, &owner.dir.join(".z"), migrate, |path| {
+        let _ = nix::unistd::chown(path, Some(owner.uid), Some(owner.gid));
+    })
+}
+
+#[cfg(not(unix))]
+fn owner_data_file(owner: &User, _migrate: bool) -> Result<PathBuf> {
+    match *owner {}
+}
+
+/// Our `zrs` data dir, moved into someone else's `home`: wherever `data_local` is relative
+/// to `our_home`, but in theirs. If it isn't inside our home, they get the XDG default.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn data_dir_for(home: &Path, data_local: Option<&Path>, our_home: Option<&Path>) -> PathBuf {
+    let relative = data_local
+        .zip(our_home)
+        .and_then(|(data, our_home)| data.strip_prefix(our_home).ok())
+        .unwrap_or(Path::new(".local/share"));
+    home.join(relative).join("zrs")
+}
+
+/// `dir/data`, making `dir` if necessary. The first time we're allowed to `migrate`, the
+/// `legacy` data file is copied in, if there is one; until then, it's used as it is.
+/// After that, it's ignored. Everything newly made is passed to `created`.
+fn data_file_in<F>(dir: &Path, legacy: &Path, migrate: bool, created: F) -> Result<PathBuf>
+where
+    F: Fn(&Path),
+{
+    let data_file = dir.join("data");
+    if data_file.exists() {
+        return Ok(data_file);
+    }
+
+    if !migrate && legacy.exists() {
+        return Ok(legacy.to_path_buf());
+    }
+
+    create_dirs(dir, &created)?;
+
+    let mut from = match fs::File::open(legacy) {
+        Ok(from) => from,
+        Err(e) if io::ErrorKind::NotFound == e.kind() => return Ok(data_file),
+        Err(e) => return Err(e).with_context(|| anyhow!("opening old data file {:?}", legacy)),
+    };
+
+    // via a temporary file, so nobody sees a half copy; another shell may be starting too
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| anyhow!("making a temporary file in {:?}", dir))?;
+    io::copy(&mut from, &mut tmp).with_context(|| anyhow!("copying {:?}", legacy))?;
+    match tmp.persist_noclobber(&data_file) {
+        Ok(_) => {
+            created(&data_file);
+            eprintln!(
+                "zrs: copied your history from {:?} to {:?}, which is used from now on",
+                legacy, data_file
+            )
+        }
+        // someone else got there first
+        Err(e) if io::ErrorKind::AlreadyExists == e.error.kind() => (),
+        Err(e) => return Err(e.error).with_context(|| anyhow!("creating {:?}", data_file)),
+    }
+
+    Ok(data_file)
+}
+
+/// `create_dir_all`, but each dir made is passed to `created`, top down
+fn create_dirs(dir: &Path, created: &dyn Fn(&Path)) -> Result<()> {
+    let missing: Vec<&Path> = dir
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && fs::metadata(dir).is_err())
+        .collect();
+
+    for dir in missing.into_iter().rev() {
+        match fs::create_dir(dir) {
+            Ok(()) => created(dir),
+            // another shell may be starting up too
+            Err(e) if io::ErrorKind::AlreadyExists == e.kind() => (),
+            Err(e) => return Err(e).with_context(|| anyhow!("creating {:?}", dir)),
         }
     }
+    Ok(())
 }
 
-fn unix_time() -> u64 {
-    time::SystemTime::now()
-        .duration_since(time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
+/// like [`warn`], but unparseable lines are left for `--fsck`, instead of complaining
+/// on every prompt; they're backed up when the file is next written
+fn warn_searching<T>(warnings: Vec<Warning>, value: T) -> T {
+    let warnings = warnings
+        .into_iter()
+        .filter(|warning| !matches!(warning, Warning::BadLine { .. }))
+        .collect();
+    warn(warnings, value)
 }
 
-fn time_delta(now: u64, then: u64) -> u64 {
-    now.saturating_sub(then)
+/// report any warnings on stderr, and carry on with the value
+fn warn<T>(warnings: Vec<Warning>, value: T) -> T {
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+    value
 }
 
 fn home_dir() -> Result<PathBuf> {
@@ -505,49 +1834,602 @@ mod tests {
     use std::path::Path;
     use std::path::PathBuf;
 
-    use super::ScoredRow;
+    #[test]
+    fn excluded() {
+        use super::is_excluded;
+        let excluded = [
+            PathBuf::from("/tmp"),
+            PathBuf::from("/home/faux/Downloads/"),
+        ];
+        assert!(is_excluded(Path::new("/tmp"), &excluded));
+        assert!(is_excluded(Path::new("/tmp/foo"), &excluded));
+        assert!(is_excluded(Path::new("/home/faux/Downloads"), &excluded));
+        assert!(is_excluded(
+            Path::new("/home/faux/Downloads/bar"),
+            &excluded
+        ));
+        assert!(!is_excluded(Path::new("/tmpfs"), &excluded));
+        assert!(!is_excluded(Path::new("/home/faux"), &excluded));
+        assert!(!is_excluded(Path::new("/tmp"), &[]));
+    }
+
+    #[test]
+    fn json() {
+        use super::json_string;
+        use super::write_json;
+        assert_eq!(r#""/home/faux""#, json_string("/home/faux"));
+        assert_eq!(r#""a\"b\\c\n\u0001""#, json_string("a\"b\\c\n\x01"));
+        assert_eq!(r#""/tmp/ünï""#, json_string("/tmp/ünï"));
+
+        let mut out = Vec::new();
+        write_json(&mut out, [].iter()).unwrap();
+        assert_eq!("[]\n", String::from_utf8(out).unwrap());
+
+        let mut out = Vec::new();
+        let row = zrs::ScoredRow {
+            rank: 3.5,
+            time: 1710000000,
+            ..scored("/home", 14.)
+        };
+        write_json(&mut out, [row].iter()).unwrap();
+        assert_eq!(
+            "[\n  {\"path\": \"/home\", \"rank\": 3.5, \"time\": 1710000000, \"score\": 14}\n]\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
 
     #[test]
-    fn pathbuf_pop() {
-        let mut p = PathBuf::from("/home/faux");
-        assert!(p.pop());
-        assert_eq!(PathBuf::from("/home"), p);
-        assert!(p.pop());
-        assert_eq!(PathBuf::from("/"), p);
-        // a path for / has no parent, but `pop()` succeeded
-        assert_eq!(None, p.parent());
-        assert!(!p.pop());
+    fn cli() {
+        use super::build_cli;
+        use clap_complete::Shell;
+
+        build_cli().debug_assert();
 
-        // further popping doesn't remove anything
-        assert_eq!(PathBuf::from("/"), p);
+        let mut out = Vec::new();
+        clap_complete::generate(Shell::Bash, &mut build_cli(), "zrs", &mut out);
+        let script = String::from_utf8(out).unwrap();
+        assert!(script.contains("--clean"));
+        assert!(script.contains("--frecent"));
     }
 
     #[test]
-    fn common() {
-        use super::common_prefix;
-        assert_eq!(None, common_prefix(&[]));
-        assert_eq!(None, common_prefix(&[s("/home")]));
-        assert_eq!(None, common_prefix(&[s("/home"), s("/etc")]));
+    #[cfg(unix)]
+    fn stdin_paths() {
+        use super::split_input;
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
         assert_eq!(
-            Some(PathBuf::from("/home")),
-            common_prefix(&[s("/home/faux"), s("/home/john")])
+            vec![PathBuf::from("/a b"), PathBuf::from("/c")],
+            split_input(b"/a b\n\n/c\n", b'\n')
         );
+        assert_eq!(
+            vec![
+                PathBuf::from("/a\nb"),
+                PathBuf::from(OsStr::from_bytes(b"/caf\xe9"))
+            ],
+            split_input(b"/a\nb\0/caf\xe9\0", b'\0')
+        );
+        assert!(split_input(b"", b'\n').is_empty());
+    }
 
+    #[test]
+    #[cfg(unix)]
+    fn list_output() {
+        use super::write_list;
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let rows = [
+            scored("/home/faux/my \"proj\"", 1.5),
+            scored(OsStr::from_bytes(b"/tmp/caf\xe9"), 12.),
+        ];
+
+        let mut out = Vec::new();
+        write_list(&mut out, &rows, true, b'\n').unwrap();
         assert_eq!(
-            Some(PathBuf::from("/home")),
-            common_prefix(&[
-                s("/home/faux"),
-                s("/home/alex/public_html"),
-                s("/home/john"),
-                s("/home/alex")
-            ])
+            b"     1.500 /home/faux/my \"proj\"\n    12.000 /tmp/caf\xe9\n".as_slice(),
+            out
         );
+
+        let mut out = Vec::new();
+        write_list(&mut out, &rows, false, b'\0').unwrap();
+        assert_eq!(b"/home/faux/my \"proj\"\0/tmp/caf\xe9\0".as_slice(), out);
+    }
+
+    #[test]
+    fn limits() {
+        use super::best;
+
+        // worst first, as search returns them
+        let table = || -> Vec<zrs::ScoredRow> {
+            [1., 5., 10.]
+                .iter()
+                .map(|&score| scored(format!("/{}", score), score))
+                .collect()
+        };
+        let scores = |rows: Vec<zrs::ScoredRow>| -> Vec<f32> {
+            // as the list and complete output see them
+            rows.into_iter().rev().map(|row| row.score).collect()
+        };
+
+        assert_eq!(vec![10., 5., 1.], scores(best(table(), None)));
+        assert_eq!(vec![10., 5.], scores(best(table(), Some(2))));
+        assert_eq!(vec![10.], scores(best(table(), Some(1))));
+        assert_eq!(vec![10., 5., 1.], scores(best(table(), Some(50))));
     }
 
-    fn s<P: AsRef<Path>>(path: P) -> ScoredRow {
-        ScoredRow {
+    #[test]
+    fn completion_terms() {
+        use super::completion_terms;
+
+        let home = Some(Path::new("/home/faux"));
+        let terms = |line| completion_terms(line, "j", home);
+
+        assert_eq!(vec!["proj", "sr"], terms("j proj  sr"));
+        assert_eq!(vec!["proj", "sr"], terms("zrs proj sr"));
+        assert_eq!(vec!["proj"], terms("/usr/local/bin/zrs proj"));
+        // only a whole word is the command
+        assert_eq!(vec!["jobs"], terms("jobs"));
+        assert_eq!(vec!["/home/faux/wo"], terms("j ~/wo/"));
+        assert_eq!(vec!["/home/faux"], terms("j ~"));
+        assert_eq!(vec!["~faux"], terms("j ~faux"));
+        assert_eq!(vec!["/usr"], terms("j /usr//"));
+        assert!(terms("j /").is_empty());
+        assert!(terms("j").is_empty());
+        assert!(terms("").is_empty());
+    }
+
+    #[test]
+    fn completions_without_children() {
+        use super::without_children;
+
+        let rows = |paths: &[&str]| -> Vec<zrs::ScoredRow> {
+            paths
+                .iter()
+                .enumerate()
+                .map(|(score, path)| scored(path, score as f32))
+                .collect()
+        };
+        let paths = |rows: Vec<zrs::ScoredRow>| -> Vec<PathBuf> {
+            rows.into_iter().map(|row| row.path).collect()
+        };
+
+        let kept = without_children(rows(&[
+            "/home/faux/proj/src",
+            "/srv/projB",
+            "/home/faux/proj/",
+            "/home/faux/proj",
+            "/home/faux/projA/src/main",
+        ]));
+
+        // projA isn't below proj, and of the two spellings of proj, the best is kept
+        assert_eq!(
+            vec![1., 3., 4.],
+            kept.iter().map(|row| row.score).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![
+                PathBuf::from("/srv/projB"),
+                PathBuf::from("/home/faux/proj"),
+                PathBuf::from("/home/faux/projA/src/main"),
+            ],
+            paths(kept)
+        );
+    }
+
+    #[test]
+    fn skips_cwd() {
+        use super::destination;
+
+        // worst first, as search returns them
+        let table: Vec<zrs::ScoredRow> = [
+            ("/home/faux/code/zrs-old", 1.),
+            ("/home/faux/code/zrs-experiments", 5.),
+            ("/home/faux/code/zrs", 10.),
+        ]
+        .iter()
+        .map(|&(path, score)| scored(path, score))
+        .collect();
+
+        let pick = |table: &[zrs::ScoredRow], here: Option<&str>, gone: &[&str]| {
+            destination(table, here.map(Path::new), |path| {
+                !gone.iter().any(|gone| path == Path::new(gone))
+            })
+            .map(|row| row.path.clone())
+        };
+
+        assert_eq!(
+            Some(PathBuf::from("/home/faux/code/zrs")),
+            pick(&table, None, &[])
+        );
+        assert_eq!(
+            Some(PathBuf::from("/home/faux/code/zrs-experiments")),
+            pick(&table, Some("/home/faux/code/zrs"), &[])
+        );
+        // trailing slashes don't hide it
+        assert_eq!(
+            Some(PathBuf::from("/home/faux/code/zrs-experiments")),
+            pick(&table, Some("/home/faux/code/zrs/"), &[])
+        );
+        // somewhere else is fine, even if it's inside the best match
+        assert_eq!(
+            Some(PathBuf::from("/home/faux/code/zrs")),
+            pick(&table, Some("/home/faux/code/zrs/src"), &[])
+        );
+        // the next best doesn't exist, so try the one after
+        assert_eq!(
+            Some(PathBuf::from("/home/faux/code/zrs-old")),
+            pick(
+                &table,
+                Some("/home/faux/code/zrs"),
+                &["/home/faux/code/zrs-experiments"]
+            )
+        );
+        // nowhere else to go
+        assert_eq!(
+            Some(PathBuf::from("/home/faux/code/zrs")),
+            pick(&table[2..], Some("/home/faux/code/zrs"), &[])
+        );
+        assert_eq!(None, pick(&table[2..], None, &["/home/faux/code/zrs"]));
+    }
+
+    #[test]
+    fn cleaning() {
+        use super::clean_table;
+        use super::Presence;
+        use zrs::store::Row;
+
+        let row = |path: &str, time: u64| Row {
+            path: PathBuf::from(path),
+            rank: 1.,
+            time,
+        };
+        let table = || {
+            vec![
+                row("/home/faux/code", 100),
+                row("/home/faux/old", 100),
+                row("/mnt/share/docs", 100),
+                row("/home/faux/code/", 10),
+                row("/home/faux/ancient", 1),
+            ]
+        };
+        let presence = |path: &Path| match path.to_str().unwrap() {
+            "/home/faux/old" => Presence::Gone,
+            "/mnt/share/docs" => Presence::Unsure("unmounted".to_string()),
+            _ => Presence::Dir,
+        };
+        let paths =
+            |table: &[Row]| -> Vec<PathBuf> { table.iter().map(|row| row.path.clone()).collect() };
+
+        let mut cleaning = table();
+        let cleaned = clean_table(&mut cleaning, 100, None, false, false, presence);
+        assert_eq!(vec![PathBuf::from("/home/faux/old")], cleaned.dead);
+        assert!(cleaned.stale.is_empty());
+        assert_eq!(
+            vec![(PathBuf::from("/mnt/share/docs"), "unmounted".to_string())],
+            cleaned.unsure
+        );
+        assert_eq!(1, cleaned.merged);
+        assert_eq!(
+            vec![
+                PathBuf::from("/home/faux/code"),
+                PathBuf::from("/mnt/share/docs"),
+                PathBuf::from("/home/faux/ancient")
+            ],
+            paths(&cleaning)
+        );
+
+        let mut cleaning = table();
+        let cleaned = clean_table(&mut cleaning, 100, Some(50), true, false, presence);
+        assert_eq!(
+            vec![
+                PathBuf::from("/home/faux/old"),
+                PathBuf::from("/mnt/share/docs")
+            ],
+            cleaned.dead
+        );
+        assert_eq!(
+            vec![
+                PathBuf::from("/home/faux/code/"),
+                PathBuf::from("/home/faux/ancient")
+            ],
+            cleaned.stale
+        );
+        assert!(cleaned.unsure.is_empty());
+        assert_eq!(vec![PathBuf::from("/home/faux/code")], paths(&cleaning));
+    }
+
+    #[test]
+    fn presences() {
+        use super::presence;
+        use super::presence_in;
+        use super::Presence;
+        use std::fs;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("home/faux/code")).unwrap();
+        fs::create_dir_all(root.join("home/faux/proj")).unwrap();
+        fs::create_dir_all(root.join("mnt/share")).unwrap();
+        fs::write(root.join("home/faux/notes"), "").unwrap();
+
+        assert_eq!(Presence::Dir, presence(&root.join("home/faux/code")));
+        assert_eq!(Presence::Gone, presence(&root.join("home/faux/old")));
+        assert_eq!(Presence::Gone, presence(&root.join("home/faux/notes")));
+        assert_eq!(Presence::Gone, presence(&root.join("home/faux/notes/x")));
+
+        // deleted the only child dir: that leaves an empty dir, but that's no mount point
+        assert_eq!(Presence::Gone, presence(&root.join("home/faux/proj/build")));
+
+        let share = root.join("mnt/share");
+        let mounts = |dir: &Path| share == dir;
+        assert!(matches!(
+            presence_in(&root.join("mnt/share/docs/work"), mounts),
+            Presence::Unsure(_)
+        ));
+        // something is mounted there, and just doesn't have this
+        fs::create_dir(share.join("photos")).unwrap();
+        assert_eq!(
+            Presence::Gone,
+            presence_in(&root.join("mnt/share/docs/work"), mounts)
+        );
+        assert_eq!(
+            Presence::Gone,
+            presence_in(&root.join("home/faux/proj/build"), mounts)
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn mount_tables() {
+        use super::mount_table_lists;
+
+        let fstab = "# /etc/fstab\n\
+            UUID=1234 / ext4 defaults 0 1\n\
+            //nas/share /mnt/share cifs noauto 0 0\n\
+            \t/dev/sdb1   /mnt/my\\040disk  ext4 defaults 0 2\n\
+            # /dev/sdc1 /mnt/old ext4 defaults 0 2\n";
+
+        assert!(mount_table_lists(fstab, Path::new("/mnt/share")));
+        assert!(mount_table_lists(fstab, Path::new("/mnt/my disk")));
+        assert!(!mount_table_lists(fstab, Path::new("/mnt/old")));
+        assert!(!mount_table_lists(fstab, Path::new("/mnt")));
+        assert!(!mount_table_lists("", Path::new("/mnt/share")));
+    }
+
+    #[test]
+    fn ages() {
+        use super::format_age;
+        assert_eq!("just now", format_age(0));
+        assert_eq!("1 second ago", format_age(1));
+        assert_eq!("59 seconds ago", format_age(59));
+        assert_eq!("1 minute ago", format_age(60));
+        assert_eq!("2 hours ago", format_age(2 * 3600 + 59 * 60));
+        assert_eq!("3 days ago", format_age(3 * 86400 + 5));
+        assert_eq!("13 days ago", format_age(13 * 86400));
+        assert_eq!("2 weeks ago", format_age(14 * 86400));
+        assert_eq!("52 weeks ago", format_age(364 * 86400));
+        assert_eq!("1 year ago", format_age(365 * 86400));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn canonical_windows_paths() {
+        use super::canonical;
+        use super::without_verbatim_prefix;
+
+        assert_eq!(
+            PathBuf::from(r"C:\Users\faux"),
+            without_verbatim_prefix(PathBuf::from(r"\\?\C:\Users\faux"))
+        );
+        // nothing plainer to say for shares
+        assert_eq!(
+            PathBuf::from(r"\\?\UNC\server\share"),
+            without_verbatim_prefix(PathBuf::from(r"\\?\UNC\server\share"))
+        );
+
+        // so stored rows line up with the current dir, for the cwd boost
+        let dir = tempfile::tempdir().unwrap();
+        let resolved = canonical(dir.path());
+        assert!(!resolved.to_string_lossy().starts_with(r"\\?\"));
+        assert!(resolved.is_dir());
+    }
+
+    #[test]
+    fn ordinals() {
+        use super::ordinal;
+        use super::percentile;
+
+        let ordinals: Vec<String> = [0, 1, 2, 3, 4, 11, 12, 13, 22, 33, 100, 101, 111]
+            .into_iter()
+            .map(ordinal)
+            .collect();
+        assert_eq!(
+            vec![
+                "0th", "1st", "2nd", "3rd", "4th", "11th", "12th", "13th", "22nd", "33rd", "100th",
+                "101st", "111th"
+            ],
+            ordinals
+        );
+
+        // as shown by --stat
+        let table: Vec<_> = [1., 2., 3.]
+            .into_iter()
+            .map(|rank| zrs::store::Row {
+                path: PathBuf::from("/a"),
+                rank,
+                time: 0,
+            })
+            .collect();
+        let shown = |rank| ordinal(percentile(rank, &table).round() as u32);
+        assert_eq!("33rd", shown(1.));
+        assert_eq!("67th", shown(2.));
+        assert_eq!("100th", shown(3.));
+    }
+
+    #[test]
+    fn ages_parse() {
+        use super::parse_age;
+        assert_eq!(Ok(30), parse_age("30"));
+        assert_eq!(Ok(30), parse_age("30s"));
+        assert_eq!(Ok(2 * 3600), parse_age("2h"));
+        assert_eq!(Ok(90 * 86400), parse_age("90d"));
+        assert_eq!(Ok(14 * 86400), parse_age("2w"));
+        assert_eq!(Ok(180 * 86400), parse_age("6m"));
+        assert_eq!(Ok(365 * 86400), parse_age("1y"));
+        assert_eq!(Ok(0), parse_age("0d"));
+
+        assert!(parse_age("").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("-5d").is_err());
+        assert!(parse_age("1.5d").is_err());
+        assert!(parse_age("5 d").is_err());
+        assert!(parse_age("5days").is_err());
+        assert!(parse_age("99999999999999999y").is_err());
+    }
+
+    #[test]
+    fn data_file_env() {
+        use super::data_file_paths;
+        use std::env;
+
+        let default = || Ok(PathBuf::from("/home/faux/.local/share/zrs/data"));
+        assert_eq!(
+            vec![PathBuf::from("/home/faux/.local/share/zrs/data")],
+            data_file_paths(None, default).unwrap()
+        );
+        assert_eq!(
+            vec![PathBuf::from("/srv/z")],
+            data_file_paths(Some("/srv/z".into()), default).unwrap()
+        );
+        // `/home/z::/srv/z:`, or with `;`s on windows
+        let listed = env::join_paths(["/home/z", "", "/srv/z", ""]).unwrap();
+        assert_eq!(
+            vec![PathBuf::from("/home/z"), PathBuf::from("/srv/z")],
+            data_file_paths(Some(listed), default).unwrap()
+        );
+        assert_eq!(
+            vec![PathBuf::from("/home/faux/.local/share/zrs/data")],
+            data_file_paths(Some("".into()), default).unwrap()
+        );
+
+        // `_Z_DATA` wins, without even looking for the default
+        let unreachable = || -> anyhow::Result<PathBuf> { panic!("not needed") };
+        assert!(data_file_paths(Some("/srv/z".into()), unreachable).is_ok());
+    }
+
+    #[test]
+    fn data_file_owner() {
+        use super::data_dir_for;
+
+        let alice = Path::new("/home/alice");
+        let data_dir = |data_local: Option<&str>, our_home: Option<&str>| {
+            data_dir_for(alice, data_local.map(Path::new), our_home.map(Path::new))
+        };
+
+        // sudo, with our HOME: theirs is in the same place in their home
+        assert_eq!(
+            PathBuf::from("/home/alice/.local/share/zrs"),
+            data_dir(Some("/root/.local/share"), Some("/root"))
+        );
+        assert_eq!(
+            PathBuf::from("/home/alice/Library/Application Support/zrs"),
+            data_dir(
+                Some("/Users/faux/Library/Application Support"),
+                Some("/Users/faux")
+            )
+        );
+        // an XDG_DATA_HOME outside our home says nothing about where theirs is
+        assert_eq!(
+            PathBuf::from("/home/alice/.local/share/zrs"),
+            data_dir(Some("/srv/xdg"), Some("/root"))
+        );
+        assert_eq!(
+            PathBuf::from("/home/alice/.local/share/zrs"),
+            data_dir(None, None)
+        );
+    }
+
+    #[test]
+    fn data_file_migration() {
+        use super::data_file_in;
+        use std::fs;
+
+        let home = tempfile::tempdir().unwrap();
+        let legacy = home.path().join(".z");
+        let dir = home.path().join(".local/share/zrs");
+        let data_file = dir.join("data");
+
+        // nothing to migrate: just somewhere to put it
+        assert_eq!(
+            data_file,
+            data_file_in(&dir, &legacy, true, |_| ()).unwrap()
+        );
+        assert!(dir.is_dir());
+        assert!(!data_file.exists());
+
+        // used where it is, until there's someone to tell about moving it
+        fs::write(&legacy, "/home/faux/code|3|100\n").unwrap();
+        assert_eq!(legacy, data_file_in(&dir, &legacy, false, |_| ()).unwrap());
+        assert!(!data_file.exists());
+
+        // then copied, the first time
+        assert_eq!(
+            data_file,
+            data_file_in(&dir, &legacy, true, |_| ()).unwrap()
+        );
+        assert_eq!(
+            "/home/faux/code|3|100\n",
+            fs::read_to_string(&data_file).unwrap()
+        );
+        assert!(legacy.exists());
+
+        // after that, the old one is left alone, even if z.sh is still writing to it
+        fs::write(&legacy, "/tmp|1|200\n").unwrap();
+        assert_eq!(
+            data_file,
+            data_file_in(&dir, &legacy, true, |_| ()).unwrap()
+        );
+        assert_eq!(
+            "/home/faux/code|3|100\n",
+            fs::read_to_string(&data_file).unwrap()
+        );
+        // and no temporary files are left lying around
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+    }
+
+    #[test]
+    fn data_file_creation() {
+        use super::data_file_in;
+        use std::cell::RefCell;
+        use std::fs;
+
+        let home = tempfile::tempdir().unwrap();
+        let legacy = home.path().join(".z");
+        fs::create_dir(home.path().join(".local")).unwrap();
+        fs::write(&legacy, "/home/faux/code|3|100\n").unwrap();
+
+        // everything new, for an owner to be given, but not what was already there
+        let created = RefCell::new(Vec::new());
+        let dir = home.path().join(".local/share/zrs");
+        let data_file = data_file_in(&dir, &legacy, true, |path| {
+            created.borrow_mut().push(path.to_path_buf())
+        })
+        .unwrap();
+        assert_eq!(
+            vec![home.path().join(".local/share"), dir.clone(), data_file],
+            created.into_inner()
+        );
+    }
+
+    /// a search result, with nothing interesting but its path and score
+    fn scored<P: AsRef<Path>>(path: P, score: f32) -> zrs::ScoredRow {
+        zrs::ScoredRow {
             path: path.as_ref().to_path_buf(),
-            score: 0.,
+            rank: 1.,
+            time: 0,
+            score,
         }
     }
 }
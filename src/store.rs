@@ -1,127 +1,404 @@
+//! Reading and writing the data file.
+//!
+//! The format is the same as z.sh's: one `path|rank|time` row per line.
+
+#[cfg(unix)]
+use std::ffi::OsStr;
 use std::fs;
 use std::io;
 use std::io::BufRead;
 use std::io::Read;
 use std::io::Write;
 use std::mem;
-use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
-use anyhow::anyhow;
-use anyhow::ensure;
-use anyhow::Context;
-use anyhow::Result;
-use nix::fcntl;
+#[cfg(unix)]
+use nix::sys::stat;
+#[cfg(unix)]
+use nix::unistd;
+#[cfg(unix)]
+use nix::unistd::Gid;
+#[cfg(unix)]
+use nix::unistd::Uid;
+#[cfg(unix)]
+use nix::unistd::User;
 use tempfile::NamedTempFile;
 
+use crate::Error;
+use crate::Outcome;
+use crate::Result;
+use crate::Warning;
+
+/// rows which have decayed below this rank are dropped when the file is written
+pub const MIN_RANK: f32 = 0.98;
+
+/// A directory we've been to.
 #[derive(Debug, Clone)]
 pub struct Row {
     pub path: PathBuf,
+    /// roughly, the number of visits, although it decays over time
     pub rank: f32,
+    /// when it was last visited, in seconds since the unix epoch
     pub time: u64,
 }
 
-fn to_row(line: &str) -> Result<Row> {
-    let mut parts = line.split('|');
+/// Parse a single `path|rank|time` line. The path is taken as raw bytes, it need not be UTF-8.
+pub fn to_row(line: &[u8]) -> Result<Row> {
+    let mut parts = line.split(|&b| b'|' == b);
 
-    let path = PathBuf::from(parts.next().ok_or_else(|| anyhow!("row needs a path"))?);
+    let path = path_from_bytes(
+        parts
+            .next()
+            .ok_or_else(|| Error::BadRow("row needs a path".to_string()))?,
+    );
 
-    let rank = parts
-        .next()
-        .ok_or_else(|| anyhow!("row needs a rank"))?
-        .parse::<f32>()?;
+    let rank = field(parts.next(), "rank")?
+        .parse::<f32>()
+        .map_err(|e| Error::BadRow(format!("invalid rank: {}", e)))?;
 
-    let time = parts
-        .next()
-        .ok_or_else(|| anyhow!("row needs a time"))?
-        .parse()?;
+    let time = field(parts.next(), "time")?
+        .parse()
+        .map_err(|e| Error::BadRow(format!("invalid time: {}", e)))?;
 
-    ensure!(
-        rank.is_finite(),
-        "file contained non-finite rank: {:?}",
-        rank
-    );
+    if !rank.is_finite() {
+        return Err(Error::BadRow(format!(
+            "file contained non-finite rank: {:?}",
+            rank
+        )));
+    }
 
     Ok(Row { path, rank, time })
 }
 
-pub fn parse<R: Read>(data_file: R) -> Result<Vec<Row>> {
+/// A path as it's written to the data file. On unix, these are the raw bytes, which
+/// needn't be UTF-8; elsewhere, the path's (UTF-8 compatible) encoding.
+pub fn path_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_encoded_bytes()
+}
+
+/// Undo [`path_bytes`]. Outside of unix, invalid UTF-8 is replaced.
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+/// Undo [`path_bytes`]. Outside of unix, invalid UTF-8 is replaced.
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn field<'l>(part: Option<&'l [u8]>, name: &str) -> Result<&'l str> {
+    let part = part.ok_or_else(|| Error::BadRow(format!("row needs a {}", name)))?;
+    str::from_utf8(part).map_err(|e| Error::BadRow(format!("invalid {}: {}", name, e)))
+}
+
+/// Read every row from a data file. Lines which can't be parsed are skipped, and returned
+/// as warnings.
+pub fn parse<R: Read>(data_file: R) -> Result<Outcome<Vec<Row>>> {
+    parse_keeping(data_file).map(|(table, _)| table)
+}
+
+/// lines which couldn't be parsed, exactly as they were read
+type Unparseable = Vec<Vec<u8>>;
+
+/// [`parse`], but also hand back the unparseable lines.
+fn parse_keeping<R: Read>(data_file: R) -> Result<(Outcome<Vec<Row>>, Unparseable)> {
     let mut ret = Vec::with_capacity(500);
-    for line in io::BufReader::new(data_file).lines() {
-        let line = line.with_context(|| anyhow!("IO error during read"))?;
+    let mut warnings = Vec::new();
+    let mut unparseable = Vec::new();
+    let mut reader = io::BufReader::new(data_file);
+    // one buffer for every line; this is on the path of every keypress in tab completion
+    let mut line = Vec::with_capacity(256);
+    let mut number = 0;
+    loop {
+        line.clear();
+        let read = reader
+            .read_until(b'\n', &mut line)
+            .map_err(|e| Error::io("IO error during read", e))?;
+        if 0 == read {
+            break;
+        }
+        number += 1;
+
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        // like `lines()`, tolerate files which have been through a windows editor
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
         match to_row(&line) {
             Ok(row) => ret.push(row),
-            Err(e) => eprintln!("couldn't parse {:?}: {:?}", line, e),
+            Err(error) => {
+                warnings.push(Warning::BadLine {
+                    number,
+                    line: String::from_utf8_lossy(&line).into_owned(),
+                    error,
+                });
+                unparseable.push(line.clone());
+            }
         }
     }
 
-    Ok(ret)
+    Ok((
+        Outcome {
+            value: ret,
+            warnings,
+        },
+        unparseable,
+    ))
+}
+
+/// Read every row from several data files, merging rows for the same path.
+///
+/// The first file is the primary one, and is created if it's missing, like
+/// [`open_data_file`]. The rest are only read, and are skipped, with a warning, if missing.
+pub fn parse_files<P: AsRef<Path>>(data_files: &[P]) -> Result<Outcome<Vec<Row>>> {
+    let (primary, rest) = match data_files.split_first() {
+        Some(split) => split,
+        None => return Err(Error::Invalid("no data files given".to_string())),
+    };
+
+    let mut table = parse(open_data_file(primary)?)?;
+
+    for data_file in rest {
+        let data_file = data_file.as_ref();
+        let file = match fs::File::open(data_file) {
+            Ok(file) => file,
+            Err(e) if io::ErrorKind::NotFound == e.kind() => {
+                table.warnings.push(Warning::MissingDataFile {
+                    path: data_file.to_path_buf(),
+                });
+                continue;
+            }
+            Err(e) => {
+                return Err(Error::io(
+                    format!("opening data file at {:?}", data_file),
+                    e,
+                ))
+            }
+        };
+        let Outcome { value, warnings } = parse(file)?;
+        table.value.extend(value);
+        table.warnings.extend(warnings);
+    }
+
+    if !rest.is_empty() {
+        crate::merge_duplicates(&mut table.value, Path::to_path_buf);
+    }
+
+    Ok(table)
+}
+
+/// how long to wait for someone else to finish with the data file, by default
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How to go about rewriting the data file.
+#[derive(Clone, Debug)]
+pub struct WriteOptions {
+    /// who should own the file afterwards; by default, whoever owns it now
+    #[cfg(unix)]
+    pub owner: Option<User>,
+    /// how long to wait for another process to release the file before giving up
+    /// with [`Error::Locked`]
+    pub lock_timeout: Duration,
+    /// write unparseable lines back as they were, instead of backing up the file and
+    /// dropping them; for writers whose warnings nobody is going to see
+    pub keep_unparseable: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions {
+            #[cfg(unix)]
+            owner: None,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            keep_unparseable: false,
+        }
+    }
 }
 
-pub fn update_file<P: AsRef<Path>, F, R>(data_file: P, apply: F) -> Result<R>
+/// Rewrite the data file under a lock, after letting `apply` modify the rows.
+///
+/// Rows with a rank below [`MIN_RANK`], or with paths containing `|` or a newline, are dropped.
+/// Lines which couldn't be parsed are dropped too, but the original file is backed up first;
+/// see [`Warning::Corrupt`]. With [`WriteOptions::keep_unparseable`], they're kept instead,
+/// and returned as [`Warning::BadLine`]s, like when reading.
+pub fn update_file<P: AsRef<Path>, F, R, E>(
+    data_file: P,
+    options: &WriteOptions,
+    apply: F,
+) -> Result<Outcome<R>, E>
 where
-    F: FnOnce(&mut Vec<Row>) -> Result<R>,
+    F: FnOnce(&mut Vec<Row>) -> Result<R, E>,
+    E: From<Error>,
 {
     let lock = open_data_file(&data_file)?;
-    fcntl::flock(lock.as_raw_fd(), fcntl::FlockArg::LockExclusive)
-        .with_context(|| anyhow!("locking"))?;
+    lock_exclusive(&lock, data_file.as_ref(), options.lock_timeout)?;
 
     // Mmm, if we pass this by value, it will be dropped immediately, which we don't want
-    let mut table = parse(&lock).with_context(|| anyhow!("parsing"))?;
+    let (
+        Outcome {
+            value: mut table,
+            mut warnings,
+        },
+        unparseable,
+    ) = parse_keeping(&lock)?;
 
-    let result = apply(&mut table).with_context(|| anyhow!("processing"))?;
+    let result = apply(&mut table)?;
 
     let tmp = NamedTempFile::new_in(
         data_file
             .as_ref()
             .parent()
-            .ok_or_else(|| anyhow!("data file cannot be at the root"))?,
+            .ok_or_else(|| Error::Invalid("data file cannot be at the root".to_string()))?,
     )
-    .with_context(|| anyhow!("couldn't make a temporary file near data file"))?;
+    .map_err(|e| Error::io("couldn't make a temporary file near data file", e))?;
 
     {
         let mut writer = io::BufWriter::new(&tmp);
         for line in table {
-            if line.rank < 0.98 {
+            if !is_written(&line) {
                 continue;
             }
 
-            let path = match line.path.to_str() {
-                Some(path) if path.contains('|') || path.contains('\n') => continue,
-                Some(path) => path,
-                None => continue,
-            };
-            writeln!(writer, "{}|{}|{}", path, line.rank, line.time)
-                .with_context(|| anyhow!("writing temporary value"))?;
+            let path = path_bytes(&line.path);
+            writer
+                .write_all(path)
+                .and_then(|()| writeln!(writer, "|{}|{}", line.rank, line.time))
+                .map_err(|e| Error::io("writing temporary value", e))?;
         }
+        if options.keep_unparseable {
+            for line in &unparseable {
+                writer
+                    .write_all(line)
+                    .and_then(|()| writer.write_all(b"\n"))
+                    .map_err(|e| Error::io("writing temporary value", e))?;
+            }
+        }
+        writer
+            .flush()
+            .map_err(|e| Error::io("writing temporary value", e))?;
     }
 
-    // best effort attempt to maintain uid/gid
-    // TODO: other attributes; mode is handled by umask.. maybe.
-    if let Ok(stat) = nix::sys::stat::stat(data_file.as_ref()) {
-        let _ = nix::unistd::chown(
-            tmp.path(),
-            Some(nix::unistd::Uid::from_raw(stat.st_uid)),
-            Some(nix::unistd::Gid::from_raw(stat.st_gid)),
-        );
+    #[cfg(unix)]
+    keep_owner(data_file.as_ref(), tmp.path(), options, &mut warnings);
+
+    let bad_lines = warnings
+        .iter()
+        .filter(|warning| matches!(warning, Warning::BadLine { .. }))
+        .count();
+    if 0 != bad_lines && !options.keep_unparseable {
+        // we're about to lose those lines; keep them somewhere someone could fix them
+        let backup = corrupt_backup_path(data_file.as_ref(), crate::unix_time());
+        fs::copy(data_file.as_ref(), &backup)
+            .map_err(|e| Error::io(format!("backing up corrupt data file to {:?}", backup), e))?;
+        warnings.retain(|warning| !matches!(warning, Warning::BadLine { .. }));
+        warnings.push(Warning::Corrupt {
+            lines: bad_lines,
+            backup,
+        });
     }
 
     tmp.persist(data_file)
-        .with_context(|| anyhow!("replacing"))?;
+        .map_err(|e| Error::io("replacing", e.error))?;
 
     // just being explicit about when we expect the lock to live to
     mem::drop(lock);
 
-    Ok(result)
+    Ok(Outcome {
+        value: result,
+        warnings,
+    })
+}
+
+/// Whether [`update_file`] will keep this row: its rank is at least [`MIN_RANK`], and its
+/// path can be stored.
+pub fn is_written(row: &Row) -> bool {
+    // these would break the format, and can't be escaped without confusing z.sh
+    let path = path_bytes(&row.path);
+    row.rank >= MIN_RANK && !path.contains(&b'|') && !path.contains(&b'\n')
+}
+
+/// Best effort attempt to maintain uid/gid.
+#[cfg(unix)]
+fn keep_owner(data_file: &Path, tmp: &Path, options: &WriteOptions, warnings: &mut Vec<Warning>) {
+    // TODO: other attributes; mode is handled by umask.. maybe.
+    let ids = match &options.owner {
+        Some(owner) => Some((owner.uid, owner.gid)),
+        None => stat::stat(data_file)
+            .ok()
+            .map(|stat| (Uid::from_raw(stat.st_uid), Gid::from_raw(stat.st_gid))),
+    };
+
+    if let Some((uid, gid)) = ids {
+        if let Err(source) = unistd::chown(tmp, Some(uid), Some(gid)) {
+            // if root is writing into someone else's file, and can't give it back, they're
+            // going to be locked out of their own data file; that's worth complaining about
+            if Uid::effective().is_root() && !uid.is_root() {
+                warnings.push(Warning::Chown {
+                    path: data_file.to_path_buf(),
+                    uid: uid.as_raw(),
+                    source: source.into(),
+                });
+            }
+        }
+    }
+}
+
+/// `~/.z` to `~/.z.corrupt-1710000000`
+fn corrupt_backup_path(data_file: &Path, now: u64) -> PathBuf {
+    let mut name = data_file.as_os_str().to_os_string();
+    name.push(format!(".corrupt-{}", now));
+    PathBuf::from(name)
+}
+
+/// Poll for the lock, so a wedged process (e.g. on a hung NFS mount) doesn't
+/// leave every future add queued up behind it, forever.
+fn lock_exclusive(lock: &fs::File, path: &Path, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut backoff = Duration::from_millis(1);
+
+    loop {
+        match lock.try_lock() {
+            Ok(()) => return Ok(()),
+            Err(fs::TryLockError::WouldBlock) => (),
+            Err(fs::TryLockError::Error(source)) => {
+                return Err(Error::Lock {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::Locked {
+                path: path.to_path_buf(),
+            });
+        }
+
+        thread::sleep(backoff.min(deadline - now));
+        backoff = (backoff * 2).min(Duration::from_millis(50));
+    }
 }
 
+/// Open (or create) the data file for reading.
 pub fn open_data_file<P: AsRef<Path>>(data_file: P) -> Result<fs::File> {
     let data_file = data_file.as_ref();
     fs::OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(data_file)
-        .with_context(|| anyhow!("opening/creating data file at {:?}", data_file))
+        .map_err(|e| Error::io(format!("opening/creating data file at {:?}", data_file), e))
 }
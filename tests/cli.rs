@@ -0,0 +1,179 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+#[test]
+fn background_add() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+    let target = dir.path().join("target");
+    fs::create_dir(&target).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_zrs"))
+        .arg("--add")
+        .arg(&target)
+        .env("_Z_DATA", &data_file)
+        .env("_Z_NO_RESOLVE_SYMLINKS", "1")
+        .status()
+        .unwrap();
+    // returns straight away, with nothing for the shell to do
+    assert_eq!(Some(70), status.code());
+
+    let expected = format!("{}|1|", target.display());
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if fs::read_to_string(&data_file)
+            .map(|data| data.starts_with(&expected))
+            .unwrap_or(false)
+        {
+            break;
+        }
+        assert!(Instant::now() < deadline, "background add never happened");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn clean_dry_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+    let kept = dir.path().join("kept");
+    let gone = dir.path().join("gone");
+    fs::create_dir(&kept).unwrap();
+    let original = format!("{}|1|100\n{}|2|100\n", kept.display(), gone.display());
+    fs::write(&data_file, &original).unwrap();
+
+    let clean = |flag: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_zrs"))
+            .arg("--clean")
+            .arg(flag)
+            .env("_Z_DATA", &data_file)
+            .env("_Z_NO_RESOLVE_SYMLINKS", "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let planned = clean("--dry-run");
+    assert_eq!(
+        format!(
+            "would remove (not a dir): {}\nWould remove 1 dead entry, merge 0 duplicates.\n",
+            gone.display()
+        ),
+        planned
+    );
+    assert_eq!(original, fs::read_to_string(&data_file).unwrap());
+
+    let done = clean("--verbose");
+    assert!(done.starts_with(&format!("removed (not a dir): {}\n", gone.display())));
+    assert!(fs::read_to_string(&data_file)
+        .unwrap()
+        .starts_with(&format!("{}|1|100\n", kept.display())));
+}
+
+#[test]
+fn limit_doesnt_change_destination() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+    let a = dir.path().join("a");
+    let b = dir.path().join("b");
+    fs::create_dir(&a).unwrap();
+    fs::create_dir(&b).unwrap();
+    fs::write(
+        &data_file,
+        format!("{}|1|100\n{}|5|100\n", a.display(), b.display()),
+    )
+    .unwrap();
+
+    let jump = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_zrs"))
+            .args(args)
+            .current_dir(&b)
+            .env("_Z_DATA", &data_file)
+            .output()
+            .unwrap();
+        assert_eq!(Some(69), output.status.code());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // b is the best, but we're already there
+    let expected = format!("{}\n", a.display());
+    assert_eq!(expected, jump(&["-r", "."]));
+    assert_eq!(expected, jump(&["-r", "-n", "1", "."]));
+}
+
+#[test]
+fn weighted_batch_add() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+    let a = dir.path().join("a");
+    let b = dir.path().join("b");
+    // can't be stored, so can't be counted
+    let piped = dir.path().join("a|b");
+
+    let add = |weight: &str| {
+        Command::new(env!("CARGO_BIN_EXE_zrs"))
+            .arg("--weight")
+            .arg(weight)
+            .arg("--add-blocking")
+            .args([&a, &b, &piped])
+            .env("_Z_DATA", &data_file)
+            .env("_Z_NO_RESOLVE_SYMLINKS", "1")
+            .output()
+            .unwrap()
+    };
+
+    // it'd be dropped as soon as it was written
+    let output = add("0.5");
+    assert!(!output.status.success());
+    assert!(!data_file.exists());
+
+    let output = add("2.5");
+    assert!(output.status.success());
+    assert_eq!(
+        "Added 2 entries, skipped 1.\n",
+        String::from_utf8(output.stdout).unwrap()
+    );
+    let written = fs::read_to_string(&data_file).unwrap();
+    assert_eq!(2, written.lines().count());
+    assert!(written.starts_with(&format!("{}|2.5|", a.display())));
+}
+
+#[test]
+fn single_blocking_add() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+
+    let add = |path: &Path| {
+        Command::new(env!("CARGO_BIN_EXE_zrs"))
+            .arg("--add-blocking")
+            .arg(path)
+            .env("_Z_DATA", &data_file)
+            .env("_Z_NO_RESOLVE_SYMLINKS", "1")
+            .output()
+            .unwrap()
+    };
+
+    // reported, like it would be in a batch, not silently ignored
+    let output = add(Path::new("relative"));
+    assert!(output.status.success());
+    assert_eq!(
+        "Added 0 entries, skipped 1.\n",
+        String::from_utf8(output.stdout).unwrap()
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("skipping (relative, home or root)"));
+
+    let output = add(dir.path());
+    assert!(output.status.success());
+    assert_eq!(
+        "Added 1 entry, skipped 0.\n",
+        String::from_utf8(output.stdout).unwrap()
+    );
+    assert_eq!(1, fs::read_to_string(&data_file).unwrap().lines().count());
+}
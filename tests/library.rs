@@ -0,0 +1,383 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use zrs::store;
+use zrs::store::WriteOptions;
+use zrs::Aging;
+use zrs::CaseMode;
+use zrs::Query;
+use zrs::Scorer;
+use zrs::Warning;
+
+fn add<P: AsRef<Path>>(data_file: &Path, path: P) {
+    let added = store::update_file(data_file, &WriteOptions::default(), |table| {
+        zrs::do_add(table, path, Aging::default());
+        Ok::<_, zrs::Error>(())
+    })
+    .unwrap();
+    assert!(added.warnings.is_empty());
+}
+
+/// `/`, or `C:\` or so on windows, where `/tmp` isn't absolute, so wouldn't be added
+fn root() -> PathBuf {
+    let temp = std::env::temp_dir();
+    temp.ancestors().last().expect("non-empty").to_path_buf()
+}
+
+fn paths(data_file: &Path, query: &Query) -> Vec<PathBuf> {
+    zrs::search(data_file, query, Scorer::Rank)
+        .unwrap()
+        .value
+        .into_iter()
+        .rev()
+        .map(|row| row.path)
+        .collect()
+}
+
+#[test]
+fn add_search_remove() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+    let zrs = root().join("home/faux/code/zrs");
+    let other = root().join("home/faux/Code/other");
+
+    add(&data_file, &zrs);
+    add(&data_file, &zrs);
+    add(&data_file, &other);
+
+    let table = store::parse(store::open_data_file(&data_file).unwrap())
+        .unwrap()
+        .value;
+    assert_eq!(2, table.len());
+    assert_eq!(2., table[0].rank);
+
+    // best first
+    assert_eq!(
+        vec![zrs.clone(), other.clone()],
+        paths(
+            &data_file,
+            &Query {
+                case: CaseMode::Insensitive,
+                ..Query::new("code")
+            }
+        )
+    );
+
+    // the same search, over a table we already have
+    let found = zrs::search_table(&table, &Query::new("Code"), Scorer::Rank).unwrap();
+    assert_eq!(1, found.len());
+    assert_eq!(other, found[0].path);
+
+    let removed = store::update_file(&data_file, &WriteOptions::default(), |table| {
+        Ok::<_, zrs::Error>(zrs::do_remove(table, &zrs))
+    })
+    .unwrap();
+    assert!(removed.value);
+
+    assert_eq!(vec![other], paths(&data_file, &Query::new("")));
+}
+
+#[test]
+fn bad_lines_are_warnings() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+    fs::write(&data_file, "/home|1|2\ngarbage\n/tmp|banana|3\n").unwrap();
+
+    let found = zrs::search(&data_file, &Query::new(""), Scorer::Rank).unwrap();
+    assert_eq!(1, found.value.len());
+
+    let numbers: Vec<usize> = found
+        .warnings
+        .iter()
+        .map(|warning| match warning {
+            Warning::BadLine { number, .. } => *number,
+            other => panic!("unexpected warning: {}", other),
+        })
+        .collect();
+    assert_eq!(vec![2, 3], numbers);
+}
+
+#[test]
+fn errors_are_typed() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+
+    match zrs::search(&data_file, &Query::new("foo("), Scorer::Rank) {
+        Err(zrs::Error::Regex { expr, .. }) => assert_eq!("foo(", expr),
+        other => panic!("expected a regex error, not {:?}", other),
+    }
+
+    assert!(matches!(Aging::new(100., 2.), Err(zrs::Error::Invalid(_))));
+}
+
+#[test]
+fn lock_timeout() {
+    use std::thread;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+
+    // locks belong to the open file, so this conflicts even within one process
+    let held = store::open_data_file(&data_file).unwrap();
+    held.lock().unwrap();
+
+    let impatient = WriteOptions {
+        lock_timeout: Duration::from_millis(50),
+        ..WriteOptions::default()
+    };
+
+    let start = Instant::now();
+    match store::update_file(&data_file, &impatient, |_| Ok::<_, zrs::Error>(())) {
+        Err(zrs::Error::Locked { path }) => assert_eq!(data_file, path),
+        other => panic!("expected to time out, not {:?}", other),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // someone else releases the lock while we're waiting
+    let releaser = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(held);
+    });
+
+    let patient = WriteOptions {
+        lock_timeout: Duration::from_secs(10),
+        ..WriteOptions::default()
+    };
+    let tmp = root().join("tmp");
+    let added = store::update_file(&data_file, &patient, |table| {
+        zrs::do_add(table, &tmp, Aging::default());
+        Ok::<_, zrs::Error>(())
+    })
+    .unwrap();
+    assert!(added.warnings.is_empty());
+    releaser.join().unwrap();
+
+    assert_eq!(vec![tmp], paths(&data_file, &Query::new("")));
+}
+
+#[test]
+#[cfg(unix)]
+fn non_utf8_round_trip() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+    fs::write(&data_file, "/home/faux/ünï|2|3\n").unwrap();
+
+    // "café" in latin-1
+    let latin1 = PathBuf::from(OsStr::from_bytes(b"/home/faux/caf\xe9"));
+
+    let added = store::update_file(&data_file, &WriteOptions::default(), |table| {
+        zrs::do_add(table, &latin1, Aging::default());
+        Ok::<_, zrs::Error>(())
+    })
+    .unwrap();
+    assert!(added.warnings.is_empty());
+
+    let written = fs::read(&data_file).unwrap();
+    assert!(written.starts_with(b"/home/faux/\xc3\xbcn\xc3\xaf|2|3\n/home/faux/caf\xe9|1|"));
+
+    let table = store::parse(store::open_data_file(&data_file).unwrap()).unwrap();
+    assert!(table.warnings.is_empty());
+    let paths: Vec<_> = table.value.into_iter().map(|row| row.path).collect();
+    assert_eq!(vec![PathBuf::from("/home/faux/ünï"), latin1.clone()], paths);
+
+    // searches match against the lossy conversion, but give back the real path
+    assert_eq!(vec![latin1], self::paths(&data_file, &Query::new("caf")));
+}
+
+#[test]
+fn multiple_data_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let personal = dir.path().join("z");
+    let work = dir.path().join("work-z");
+    let missing = dir.path().join("missing-z");
+    fs::write(&personal, "/home/faux/code|2|100\n/tmp|1|100\n").unwrap();
+    fs::write(&work, "/home/faux/code|3|200\n/srv/app|4|50\n").unwrap();
+
+    let found =
+        zrs::search_files(&[&personal, &missing, &work], &Query::new(""), Scorer::Rank).unwrap();
+
+    match found.warnings.as_slice() {
+        [Warning::MissingDataFile { path }] => assert_eq!(&missing, path),
+        other => panic!("expected a missing file warning, not {:?}", other),
+    }
+
+    // merged, not double-listed
+    let rows: Vec<_> = found
+        .value
+        .iter()
+        .rev()
+        .map(|row| (row.path.to_str().unwrap(), row.rank, row.time))
+        .collect();
+    assert_eq!(
+        vec![
+            ("/home/faux/code", 5., 200),
+            ("/srv/app", 4., 50),
+            ("/tmp", 1., 100)
+        ],
+        rows
+    );
+
+    // only the primary is created
+    assert!(!missing.exists());
+}
+
+#[test]
+fn corrupt_lines_are_backed_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+    let home = root().join("home");
+    let srv = root().join("srv");
+    let original = format!(
+        "{}|1|2\n/truncated|4\n/tmp|banana|3\n<<<<<<< HEAD\n{}|3|4\n",
+        home.display(),
+        srv.display()
+    );
+    fs::write(&data_file, &original).unwrap();
+
+    // reading just reports the lines, it doesn't touch anything
+    let found = zrs::search(&data_file, &Query::new(""), Scorer::Rank).unwrap();
+    assert_eq!(2, found.value.len());
+    assert_eq!(3, found.warnings.len());
+
+    let added = store::update_file(&data_file, &WriteOptions::default(), |table| {
+        zrs::do_add(table, &srv, Aging::default());
+        Ok::<_, zrs::Error>(())
+    })
+    .unwrap();
+
+    let backup = match added.warnings.as_slice() {
+        [Warning::Corrupt { lines: 3, backup }] => backup.clone(),
+        other => panic!("expected one summary, not {:?}", other),
+    };
+    assert_eq!(original, fs::read_to_string(backup).unwrap());
+
+    assert_eq!(vec![srv, home], paths(&data_file, &Query::new("")));
+
+    // now it's clean, so nothing more is backed up
+    add(&data_file, root().join("tmp"));
+    assert_eq!(2, fs::read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn large_data_file() {
+    use std::fmt::Write as _;
+
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+
+    // deterministic junk, spread over a few roots, so there's no common prefix to boost
+    let mut seed: u64 = 0x5eed;
+    let mut next = move || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        seed >> 33
+    };
+    let words = [
+        "src", "Code", "zrs", "target", "docs", "proj", "tmp", "build",
+    ];
+    let mut contents = String::new();
+    for i in 0..50_000 {
+        let mut path = format!("/home/user{}", next() % 5);
+        for _ in 0..(1 + next() % 4) {
+            write!(path, "/{}", words[(next() % words.len() as u64) as usize]).unwrap();
+        }
+        write!(path, "-{}", i).unwrap();
+        writeln!(
+            contents,
+            "{}|{}|{}",
+            path,
+            1 + next() % 1000,
+            next() % 100_000
+        )
+        .unwrap();
+    }
+    fs::write(&data_file, contents).unwrap();
+
+    let table = store::parse(store::open_data_file(&data_file).unwrap()).unwrap();
+    assert!(table.warnings.is_empty());
+    let table = table.value;
+    assert_eq!(50_000, table.len());
+
+    // the straightforward way: filter, then sort, stably, by rank
+    let expected = |pattern: &str, insensitive| -> Vec<PathBuf> {
+        let regex = regex::RegexBuilder::new(pattern)
+            .case_insensitive(insensitive)
+            .build()
+            .unwrap();
+        let mut rows: Vec<_> = table
+            .iter()
+            .filter(|row| regex.is_match(row.path.to_str().unwrap()))
+            .collect();
+        rows.sort_by(|left, right| left.rank.partial_cmp(&right.rank).unwrap());
+        rows.into_iter().map(|row| row.path.clone()).collect()
+    };
+
+    for (pattern, insensitive) in [
+        // smart case: matches as typed
+        ("Code.*zrs", false),
+        ("docs.*-4999", false),
+        // smart case: nothing has "CODE", so falls back to ignoring case
+        ("CODE/SRC", true),
+    ] {
+        let query = Query {
+            case: CaseMode::Smart,
+            ..Query::new(pattern)
+        };
+        let expected = expected(pattern, insensitive);
+        assert!(!expected.is_empty(), "{}", pattern);
+
+        let found: Vec<_> = zrs::search(&data_file, &query, Scorer::Rank)
+            .unwrap()
+            .value
+            .into_iter()
+            .map(|row| row.path)
+            .collect();
+        assert_eq!(expected, found, "{}", pattern);
+
+        let found: Vec<_> = zrs::search_table(&table, &query, Scorer::Rank)
+            .unwrap()
+            .into_iter()
+            .map(|row| row.path)
+            .collect();
+        assert_eq!(expected, found, "{}", pattern);
+    }
+}
+
+#[test]
+fn unparseable_lines_can_be_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_file = dir.path().join("z");
+    fs::write(&data_file, b"/home|1|2\n/truncated|4\n/caf\xe9|banana|3\n").unwrap();
+
+    let keeping = WriteOptions {
+        keep_unparseable: true,
+        ..WriteOptions::default()
+    };
+    let srv = root().join("srv");
+    let added = store::update_file(&data_file, &keeping, |table| {
+        zrs::do_add(table, &srv, Aging::default());
+        Ok::<_, zrs::Error>(())
+    })
+    .unwrap();
+
+    // reported like when reading, but nothing is backed up
+    assert_eq!(2, added.warnings.len());
+    assert!(added
+        .warnings
+        .iter()
+        .all(|warning| matches!(warning, Warning::BadLine { .. })));
+    assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+
+    // the good rows are rewritten, the rest are left exactly as they were
+    let written = fs::read(&data_file).unwrap();
+    let rows = format!("/home|1|2\n{}|1|", srv.display());
+    assert!(written.starts_with(rows.as_bytes()));
+    assert!(written.ends_with(b"\n/truncated|4\n/caf\xe9|banana|3\n"));
+}
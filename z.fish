@@ -0,0 +1,54 @@
+# Copyright (c) 2018 Chris West. Licensed under MIT OR Apache-2.0
+# Parts Copyright (c) 2009 rupa deadwyler. Licensed under the WTFPL license, Version 2
+
+# maintains a jump-list of the directories you actually use
+#
+# INSTALL:
+#     * put something like this in your ~/.config/fish/config.fish:
+#         source /path/to/z.fish
+#     * cd around for a while to build up the db
+#     * PROFIT!!
+#     * optionally:
+#         set $_Z_CMD in config.fish to change the command (default z).
+#         set -gx $_Z_DATA in config.fish to change the datafile (default ~/.local/share/zrs/data;
+#         an existing ~/.z is copied there the first time).
+#         set -gx $_Z_NO_RESOLVE_SYMLINKS to prevent symlink resolution.
+#         set $_Z_NO_PROMPT_COMMAND if you're handling adding yourself.
+#         the other settings in z.sh work too, as long as they're exported with set -gx.
+#
+# USE: see z.sh
+
+function __z
+    set -l output (command zrs $argv)
+    set -l ret $status
+    switch $ret
+        case 69
+            # 69: DoCd
+            builtin cd $output
+        case 70
+            # 70: NoOutput
+        case 0
+            # 0: Success
+            printf '%s\n' $output
+        case '*'
+            echo "zrs failed: $ret"
+    end
+end
+
+set -q _Z_CMD; or set -g _Z_CMD z
+
+function $_Z_CMD --description 'jump to a recently used directory'
+    __z $argv 2>&1
+end
+
+if not set -q _Z_NO_PROMPT_COMMAND
+    # populate directory list whenever the working directory changes
+    function __z_add --on-variable PWD
+        set -l dir $PWD
+        set -q _Z_NO_RESOLVE_SYMLINKS; or set dir (builtin realpath $PWD)
+        command zrs --add $dir 2>/dev/null
+    end
+end
+
+# tab completion
+complete -c $_Z_CMD -f -a '(command zrs --complete (commandline -cp))'
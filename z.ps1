@@ -0,0 +1,42 @@
+# zrs helper for PowerShell; dot-source this from your $PROFILE:
+#     . 'C:\Users\you\AppData\Local\zrs\z.ps1'
+#
+# USE:     z foo       # cd to most frecent dir matching foo
+#          z foo bar   # cd to most frecent dir matching foo and bar
+#          z -r foo    # cd to highest ranked dir matching foo
+#          z -t foo    # cd to most recently accessed dir matching foo
+#          z -l foo    # list matches instead of cd
+#          z -c foo    # restrict matches to subdirs of $PWD
+#
+# OPTIONS: set $env:_Z_DATA to change the datafile (default %LOCALAPPDATA%\zrs\data).
+#          set $env:_Z_EXCLUDE_DIRS as a semicolon separated list of directories to exclude.
+
+function global:z {
+    $out = & zrs @args
+    switch ($LASTEXITCODE) {
+        # the binary found somewhere to go
+        69 { Set-Location -LiteralPath ($out | Select-Object -Last 1) }
+        # nothing to do
+        70 { }
+        default { $out }
+    }
+}
+
+# record every directory we're in, when the prompt is drawn
+$global:__zrs_prompt = $function:prompt
+function global:prompt {
+    $code = $global:LASTEXITCODE
+    $here = $ExecutionContext.SessionState.Path.CurrentLocation
+    if ($here.Provider.Name -eq 'FileSystem') {
+        & zrs --add $here.ProviderPath 2>$null | Out-Null
+    }
+    $global:LASTEXITCODE = $code
+    & $global:__zrs_prompt
+}
+
+Register-ArgumentCompleter -CommandName z -ScriptBlock {
+    param($wordToComplete, $commandAst, $cursorPosition)
+    & zrs --complete "$commandAst" | ForEach-Object {
+        [System.Management.Automation.CompletionResult]::new("'$_'", $_, 'ParameterValue', $_)
+    }
+}
@@ -10,11 +10,19 @@
 #     * PROFIT!!
 #     * optionally:
 #         set $_Z_CMD in .bashrc/.zshrc to change the command (default z).
-#         set $_Z_DATA in .bashrc/.zshrc to change the datafile (default ~/.z).
-#         TODO: set $_Z_NO_RESOLVE_SYMLINKS to prevent symlink resolution.
+#         set $_Z_DATA in .bashrc/.zshrc to change the datafile (default ~/.local/share/zrs/data;
+#         an existing ~/.z is copied there the first time).
+#         $_Z_DATA can be a colon separated list; all are searched, only the first is written.
+#         export $_Z_NO_RESOLVE_SYMLINKS to prevent symlink resolution.
 #         set $_Z_NO_PROMPT_COMMAND if you're handling PROMPT_COMMAND yourself.
-#         TODO: set $_Z_EXCLUDE_DIRS to an array of directories to exclude.
-#         TODO: set $_Z_OWNER to your username if you want use z while sudo with $HOME kept
+#         export $_Z_MAX_SCORE to change how large ranks get before aging (default 9000).
+#         export $_Z_EXCLUDE_DIRS as a colon separated list of directories to exclude.
+#         export $_Z_OWNER as your username if you want use z while sudo with $HOME kept
+#         export $_Z_LOCK_TIMEOUT_MS to change how long to wait for a busy datafile (default 2000).
+#         export $_Z_CWD_BOOST to change how much dirs below the current one are preferred (default 3).
+#         export $_Z_MATCH=unordered to let `z foo bar` also find bar/foo.
+#         export $_Z_FRECENCY=smooth to avoid sudden jumps in ranking at hour, day and week boundaries.
+#         export $_Z_COMPLETE_LIMIT to change how many completions are offered (default 50, 0 for all).
 #
 # USE:
 #     * z foo     # cd to most frecent dir matching foo
@@ -24,9 +32,10 @@
 #     * z -l foo  # list matches instead of cd
 #     * z -e foo  # echo the best match, don't cd
 #     * z -c foo  # restrict matches to subdirs of $PWD
+#     * z -a foo bar # cd to most frecent dir matching foo and bar, in either order
 
-[ -d "${_Z_DATA:-$HOME/.z}" ] && {
-    echo "ERROR: z.sh's datafile (${_Z_DATA:-$HOME/.z}) is a directory."
+[ -n "$_Z_DATA" ] && [ -d "$_Z_DATA" ] && {
+    echo "ERROR: z.sh's datafile ($_Z_DATA) is a directory."
 }
 
 _z() {